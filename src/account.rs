@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use log::debug;
+
+use crate::{
+  auth::{OAuthClient, ServiceUrls},
+  config::Config,
+  fitbit::FitbitClient,
+  vault::SharedVault,
+};
+
+pub type AccountId = String;
+
+/// The account used when none is configured, and by destinations that don't name one.
+pub static DEFAULT_ACCOUNT: &str = "default";
+
+/// A Fitbit user whose data is synced, with its own tokens.
+pub struct Account {
+  pub id: AccountId,
+  pub fitbit_client: FitbitClient,
+}
+
+impl Account {
+  pub fn fitbit_oauth(
+    id: &str,
+    config: &Config,
+    project_dirs: &ProjectDirs,
+    vault: SharedVault,
+  ) -> Result<OAuthClient> {
+    OAuthClient::for_service(
+      "fitbit",
+      ServiceUrls::fitbit(&config.fitbit_base_url),
+      id,
+      &config.auth.fitbit,
+      &config.server.public_url(),
+      project_dirs,
+      vault,
+    )
+  }
+}
+
+pub struct Accounts {
+  accounts: Vec<Account>,
+}
+
+impl Accounts {
+  pub fn load(config: &Config, project_dirs: &ProjectDirs, vault: &SharedVault) -> Result<Self> {
+    let mut accounts = Vec::new();
+    for id in config.accounts.iter() {
+      let oauth = Account::fitbit_oauth(id, config, project_dirs, vault.clone())?;
+      accounts.push(Account {
+        id: id.to_owned(),
+        fitbit_client: FitbitClient::new(oauth, &config.fitbit_base_url, &config.fitbit_units),
+      });
+    }
+
+    Ok(Accounts { accounts })
+  }
+
+  pub fn get(&self, id: &str) -> Result<&Account> {
+    self
+      .accounts
+      .iter()
+      .find(|a| a.id == id)
+      .ok_or_else(|| anyhow!("No account named {}", id))
+  }
+
+  /// Finds the account that's waiting for an authorization callback with `state`.
+  pub fn find_pending(&self, state: &str) -> Option<&Account> {
+    self.accounts.iter().find(|a| {
+      let oauth = a.fitbit_client.oauth.lock().unwrap();
+      oauth.is_pending(state)
+    })
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Account> {
+    self.accounts.iter()
+  }
+
+  /// The accounts that have been authorized.
+  pub fn authorized(&self) -> impl Iterator<Item = &Account> {
+    self.accounts.iter().filter(|a| {
+      let authorized = a.fitbit_client.oauth.lock().unwrap().has_secret();
+      if !authorized {
+        debug!("Account {} hasn't been authorized", a.id);
+      }
+      authorized
+    })
+  }
+}
@@ -1,97 +1,268 @@
-use anyhow::Result;
+use std::sync::Arc;
 
-use crate::sync::SyncSession;
-use crate::AppState;
-use log::info;
+use anyhow::{anyhow, Result};
+
+use fitsync::account::DEFAULT_ACCOUNT;
+use fitsync::destination::DestinationStatus;
+use fitsync::fitbit::{Device, RateLimit};
+use fitsync::sheets::SheetsClient;
+use fitsync::sync::SyncSession;
+use fitsync::AppState;
+use log::{error, info};
 use rocket::response::Redirect;
 use rocket::{Route, State};
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
 
-static FITBIT_SCOPES: &str =
-  "activity heartrate location nutrition profile settings sleep social weight";
-
 #[derive(Serialize, Deserialize, Debug)]
 struct ServiceAuthState {
+  account: String,
   has_token: bool,
-  scopes: String,
-  redirect_uri: String,
-  client_id: String,
+  needs_reauth: bool,
+  missing_scopes: Vec<String>,
+  start_url: String,
 }
 
 impl ServiceAuthState {
-  fn create_fitbit(has_token: bool, client_id: String) -> Self {
+  fn create_fitbit(
+    account: &str,
+    has_token: bool,
+    needs_reauth: bool,
+    missing_scopes: Vec<String>,
+  ) -> Self {
     ServiceAuthState {
+      account: account.to_owned(),
       has_token,
-      scopes: FITBIT_SCOPES.to_owned(),
-      redirect_uri: "http://localhost:8000/auth/fitbit".to_owned(), // FIXME: port
-      client_id,
+      needs_reauth,
+      missing_scopes,
+      start_url: format!("/auth/fitbit/start?account={}", account),
     }
   }
 
-  fn create_google(has_token: bool, client_id: String) -> Self {
+  fn create_google(has_token: bool, needs_reauth: bool) -> Self {
     ServiceAuthState {
+      account: DEFAULT_ACCOUNT.to_owned(),
       has_token,
-      scopes: "".to_owned(),
-      redirect_uri: "http://localhost:8000/".to_owned(),
-      client_id,
+      needs_reauth,
+      missing_scopes: vec![],
+      start_url: "/auth/google/start".to_owned(),
     }
   }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AuthState {
-  fitbit: ServiceAuthState,
+  fitbit: Vec<ServiceAuthState>,
   google: ServiceAuthState,
 }
 
 #[get("/authstate")]
-fn authstate(state: State<AppState>) -> Result<Json<AuthState>> {
-  let locked_oauth = state.fitbit_client.oauth.lock().unwrap();
-  let has_fitbit_token = locked_oauth.has_secret();
-  let has_google_token = false;
+fn authstate(state: State<Arc<AppState>>) -> Result<Json<AuthState>> {
+  let fitbit = state
+    .accounts
+    .iter()
+    .map(|account| {
+      let locked_oauth = account.fitbit_client.oauth.lock().unwrap();
+      ServiceAuthState::create_fitbit(
+        &account.id,
+        locked_oauth.has_secret(),
+        locked_oauth.needs_reauthorization(),
+        locked_oauth.missing_scopes(),
+      )
+    })
+    .collect();
+  let google = match state.sheets_client {
+    Some(ref client) => {
+      let locked_oauth = client.oauth.lock().unwrap();
+      ServiceAuthState::create_google(
+        locked_oauth.has_secret(),
+        locked_oauth.needs_reauthorization(),
+      )
+    }
+    None => ServiceAuthState::create_google(false, false),
+  };
+
+  Ok(Json(AuthState { fitbit, google }))
+}
 
-  Ok(Json(AuthState {
-    fitbit: ServiceAuthState::create_fitbit(has_fitbit_token, locked_oauth.get_client_id()),
-    google: ServiceAuthState::create_google(has_google_token, "".to_owned()),
-  }))
+fn sheets_client(state: &AppState) -> Result<&SheetsClient> {
+  state
+    .sheets_client
+    .as_deref()
+    .ok_or_else(|| anyhow!("Google isn't configured in config.json"))
 }
 
-#[get("/fitbit?<code>")]
-fn fitbit_auth(code: Option<String>, state: State<AppState>) -> Result<Redirect> {
+#[get("/fitbit/start?<account>")]
+fn fitbit_auth_start(account: Option<String>, state: State<Arc<AppState>>) -> Result<Redirect> {
+  let account = state
+    .accounts
+    .get(account.as_deref().unwrap_or(DEFAULT_ACCOUNT))?;
+  let mut oauth = account.fitbit_client.oauth.lock().expect("unable to lock");
+  Ok(Redirect::to(oauth.authorize_url().to_string()))
+}
+
+#[get("/fitbit?<code>&<state>")]
+fn fitbit_auth(
+  code: Option<String>,
+  state: Option<String>,
+  app_state: State<Arc<AppState>>,
+) -> Result<Redirect> {
   info!("fitbit_auth started");
-  match code {
-    Some(code) => {
+  match (code, state) {
+    (Some(code), Some(state)) => {
       {
-        let mut oauth = state.fitbit_client.oauth.lock().expect("unable to lock");
-        oauth.obtain_tokens(code)?;
+        let account = app_state
+          .accounts
+          .find_pending(&state)
+          .ok_or_else(|| anyhow!("No authorization in progress with this state"))?;
+        info!("Authorizing account {}", account.id);
+        let mut oauth = account.fitbit_client.oauth.lock().expect("unable to lock");
+        oauth.obtain_tokens(code, state)?;
       }
 
-      // Force a sync to happen now.
       info!("fitbit_auth is requesting a sync");
-      sync(state)?;
+      sync_in_background(app_state.inner());
 
       info!("fitbit_auth done, redirecting");
       Ok(Redirect::to("/"))
     }
-    None => {
-      info!("fitbit_auth called with no code");
+    _ => {
+      info!("fitbit_auth called with no code or state");
+      Ok(Redirect::to("/"))
+    }
+  }
+}
+
+/// Syncs every destination now, without holding up the redirect back to the UI. The
+/// tokens are already stored, so a failure is only logged.
+fn sync_in_background(state: &Arc<AppState>) {
+  let state = state.clone();
+  std::thread::spawn(move || {
+    match SyncSession::start(&state.destinations, &state.accounts).sync_all() {
+      Ok(()) => info!("Sync completed"),
+      Err(e) => error!("Sync failed: {:?}", e),
+    }
+  });
+}
+
+#[post("/auth/fitbit/revoke?<account>")]
+fn fitbit_revoke(account: Option<String>, state: State<Arc<AppState>>) -> Result<()> {
+  let account = state
+    .accounts
+    .get(account.as_deref().unwrap_or(DEFAULT_ACCOUNT))?;
+  let mut oauth = account.fitbit_client.oauth.lock().expect("unable to lock");
+  oauth.revoke_tokens()
+}
+
+#[get("/google/start")]
+fn google_auth_start(state: State<Arc<AppState>>) -> Result<Redirect> {
+  let mut oauth = sheets_client(&state)?.oauth.lock().expect("unable to lock");
+  Ok(Redirect::to(oauth.authorize_url().to_string()))
+}
+
+#[get("/google?<code>&<state>")]
+fn google_auth(
+  code: Option<String>,
+  state: Option<String>,
+  app_state: State<Arc<AppState>>,
+) -> Result<Redirect> {
+  info!("google_auth started");
+  match (code, state) {
+    (Some(code), Some(state)) => {
+      {
+        let mut oauth = sheets_client(&app_state)?
+          .oauth
+          .lock()
+          .expect("unable to lock");
+        oauth.obtain_tokens(code, state)?;
+      }
+
+      info!("google_auth is requesting a sync");
+      sync_in_background(app_state.inner());
+
+      Ok(Redirect::to("/"))
+    }
+    _ => {
+      info!("google_auth called with no code or state");
       Ok(Redirect::to("/"))
     }
   }
 }
 
+#[post("/auth/google/revoke")]
+fn google_revoke(state: State<Arc<AppState>>) -> Result<()> {
+  let mut oauth = sheets_client(&state)?.oauth.lock().expect("unable to lock");
+  oauth.revoke_tokens()
+}
+
+#[derive(Serialize)]
+struct AccountStatus {
+  account: String,
+  rate_limit: Option<RateLimit>,
+}
+
+#[derive(Serialize)]
+struct Status {
+  accounts: Vec<AccountStatus>,
+  syncing: bool,
+  /// Not available while a sync is in progress.
+  destinations: Option<Vec<DestinationStatus>>,
+}
+
+#[get("/status")]
+fn status(state: State<Arc<AppState>>) -> Json<Status> {
+  let accounts = state
+    .accounts
+    .iter()
+    .map(|account| AccountStatus {
+      account: account.id.to_owned(),
+      rate_limit: account.fitbit_client.rate_limit(),
+    })
+    .collect();
+  // A sync holds the lock throughout, which can be a long time if it's waiting for
+  // the rate limit to reset.
+  let destinations = state.destinations.try_lock().ok().map(|d| d.statuses());
+
+  Json(Status {
+    accounts,
+    syncing: destinations.is_none(),
+    destinations,
+  })
+}
+
+/// The devices paired with an account, with their battery levels and when they last
+/// synced with Fitbit.
+#[get("/devices?<account>")]
+fn devices(account: Option<String>, state: State<Arc<AppState>>) -> Result<Json<Vec<Device>>> {
+  let account = state
+    .accounts
+    .get(account.as_deref().unwrap_or(DEFAULT_ACCOUNT))?;
+  Ok(Json(account.fitbit_client.devices()?))
+}
+
 #[get("/sync")]
-fn sync(state: State<AppState>) -> Result<()> {
-  SyncSession::start(&state.destinations, &state.fitbit_client).sync_all()?;
+fn sync(state: State<Arc<AppState>>) -> Result<()> {
+  SyncSession::start(&state.destinations, &state.accounts).sync_all()?;
 
   Ok(())
 }
 
 pub fn get_api_routes() -> Vec<Route> {
-  routes![authstate, sync]
+  routes![
+    authstate,
+    devices,
+    fitbit_revoke,
+    google_revoke,
+    status,
+    sync
+  ]
 }
 
 pub fn get_auth_routes() -> Vec<Route> {
-  routes![fitbit_auth]
+  routes![
+    fitbit_auth_start,
+    fitbit_auth,
+    google_auth_start,
+    google_auth
+  ]
 }
@@ -1,105 +1,553 @@
-use std::collections::HashMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::PathBuf;
 
 use anyhow::{anyhow, Result};
-use lazy_static::lazy_static;
-use oauth2::basic::BasicTokenResponse;
-use oauth2::reqwest::http_client;
+use chrono::{DateTime, Duration, Utc};
+use directories::ProjectDirs;
+use log::{info, warn};
+use oauth2::basic::{BasicErrorResponse, BasicErrorResponseType, BasicTokenResponse};
+use oauth2::reqwest::{http_client, HttpClientError};
+use oauth2::url::Url;
 use oauth2::{basic::BasicClient, AuthUrl, AuthorizationCode, ClientId, TokenUrl};
-use oauth2::{ClientSecret, RedirectUrl, TokenResponse};
-use serde::Deserialize;
-
-static FITBIT: &str = "fitbit";
-
-lazy_static! {
-  static ref URLS: HashMap<&'static str, ServiceUrls> = {
-    let mut m = HashMap::new();
-    m.insert(
-      FITBIT,
-      ServiceUrls {
-        auth_url: "https://www.fitbit.com/oauth2/authorize".to_owned(),
-        token_url: "https://api.fitbit.com/oauth2/token".to_owned(),
-        redirect_url_path: "/auth/fitbit".to_owned(),
-      },
-    );
-    m
-  };
-}
+use oauth2::{ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope};
+use oauth2::{RequestTokenError, TokenResponse};
+use oauth2::{RevocationUrl, StandardRevocableToken};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::account::DEFAULT_ACCOUNT;
+use crate::vault::{SharedVault, Vault};
+
+/// How long before the access token expires that it's proactively refreshed.
+const REFRESH_MARGIN_MINUTES: i64 = 5;
 
-struct ServiceUrls {
+/// The endpoints and parameters used to authorize with a service.
+pub struct ServiceUrls {
   auth_url: String,
   token_url: String,
+  revocation_url: Option<String>,
   redirect_url_path: String,
+  scopes: String,
+  extra_auth_params: Vec<(&'static str, &'static str)>,
+}
+
+impl ServiceUrls {
+  /// Fitbit's URLs, with the token endpoints under `api_base_url` (normally
+  /// `https://api.fitbit.com`).
+  pub fn fitbit(api_base_url: &str) -> Self {
+    let api_base_url = api_base_url.trim_end_matches('/');
+    ServiceUrls {
+      auth_url: "https://www.fitbit.com/oauth2/authorize".to_owned(),
+      token_url: format!("{}/oauth2/token", api_base_url),
+      revocation_url: Some(format!("{}/oauth2/revoke", api_base_url)),
+      redirect_url_path: "/auth/fitbit".to_owned(),
+      scopes: "activity cardio_fitness heartrate location nutrition oxygen_saturation profile \
+               respiratory_rate settings sleep social temperature weight"
+        .to_owned(),
+      extra_auth_params: vec![],
+    }
+  }
+
+  /// Google's URLs, with the token endpoints under `oauth_base_url` (normally
+  /// `https://oauth2.googleapis.com`).
+  pub fn google(oauth_base_url: &str) -> Self {
+    let oauth_base_url = oauth_base_url.trim_end_matches('/');
+    ServiceUrls {
+      auth_url: "https://accounts.google.com/o/oauth2/v2/auth".to_owned(),
+      token_url: format!("{}/token", oauth_base_url),
+      revocation_url: Some(format!("{}/revoke", oauth_base_url)),
+      redirect_url_path: "/auth/google".to_owned(),
+      scopes: "https://www.googleapis.com/auth/spreadsheets".to_owned(),
+      // Google only issues a refresh token for offline access, and only on first
+      // consent unless asked again.
+      extra_auth_params: vec![("access_type", "offline"), ("prompt", "consent")],
+    }
+  }
 }
 
 #[derive(Deserialize)]
 
 pub struct ServiceClient {
   pub id: String,
+  #[serde(default)]
   pub secret: String,
 }
 
+/// Tokens as written to disk. The expiry is stored as an absolute time, since the
+/// `expires_in` of the token response is relative to when it was issued.
+#[derive(Serialize, Deserialize)]
+struct StoredTokens {
+  tokens: BasicTokenResponse,
+  expires_at: Option<DateTime<Utc>>,
+}
+
+/// Persists the tokens for an account with a service in the credential vault.
+pub struct TokenStore {
+  vault: SharedVault,
+  name: String,
+  legacy_path: Option<PathBuf>,
+}
+
+impl TokenStore {
+  pub fn new(
+    project_dirs: &ProjectDirs,
+    vault: SharedVault,
+    service_name: &str,
+    account_id: &str,
+  ) -> Self {
+    // The default account keeps the names used before multiple accounts were
+    // supported.
+    if account_id == DEFAULT_ACCOUNT {
+      TokenStore {
+        vault,
+        name: format!("token.{}", service_name),
+        legacy_path: Some(
+          project_dirs
+            .data_dir()
+            .join("tokens")
+            .join(format!("{}.json", service_name)),
+        ),
+      }
+    } else {
+      TokenStore {
+        vault,
+        name: format!("token.{}.{}", service_name, account_id),
+        legacy_path: None,
+      }
+    }
+  }
+
+  fn load(&self) -> Result<Option<StoredTokens>> {
+    self.load_from(&mut self.vault.lock().unwrap())
+  }
+
+  fn load_from(&self, vault: &mut Vault) -> Result<Option<StoredTokens>> {
+    if let Some(value) = vault.get(&self.name) {
+      return Ok(Some(serde_json::from_str(value)?));
+    }
+
+    // Tokens used to be stored in plaintext. Move them into the vault.
+    if let Some(ref legacy_path) = self.legacy_path {
+      if legacy_path.exists() {
+        warn!(
+          "Moving plaintext tokens from {:?} into the vault",
+          legacy_path
+        );
+        let value = read_to_string(legacy_path)?;
+        let tokens = serde_json::from_str(&value)?;
+        vault.set(&self.name, value)?;
+        std::fs::remove_file(legacy_path)?;
+        return Ok(Some(tokens));
+      }
+    }
+
+    Ok(None)
+  }
+
+  fn save(&self, vault: &mut Vault, tokens: &StoredTokens) -> Result<()> {
+    let ser = serde_json::to_string(tokens)?;
+    vault.set(&self.name, ser)
+  }
+
+  fn clear(&self, vault: &mut Vault) -> Result<()> {
+    vault.remove(&self.name)
+  }
+}
+
+/// Why an access token couldn't be obtained.
+#[derive(Debug)]
+pub enum TokenError {
+  /// There are no tokens, or the refresh token was rejected. The user has to
+  /// authorize again.
+  Unauthorized(String),
+  /// The token endpoint couldn't be reached. The tokens are kept.
+  Network(reqwest::Error),
+  /// Any other failure, such as an unexpected response or the vault failing to save.
+  Other(anyhow::Error),
+}
+
+impl fmt::Display for TokenError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TokenError::Unauthorized(message) => write!(f, "{}", message),
+      TokenError::Network(e) => write!(f, "Couldn't reach the token endpoint: {}", e),
+      TokenError::Other(e) => write!(f, "{:#}", e),
+    }
+  }
+}
+
+impl From<anyhow::Error> for TokenError {
+  fn from(e: anyhow::Error) -> Self {
+    TokenError::Other(e)
+  }
+}
+
+impl std::error::Error for TokenError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      TokenError::Network(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+/// Whether the token endpoint rejected the refresh token. Fitbit reports this in its
+/// own error format rather than OAuth's, so both are checked.
+fn is_invalid_grant(error: &RequestTokenError<HttpClientError, BasicErrorResponse>) -> bool {
+  match error {
+    RequestTokenError::ServerResponse(response) => {
+      *response.error() == BasicErrorResponseType::InvalidGrant
+    }
+    RequestTokenError::Parse(_, body) => serde_json::from_slice::<Value>(body)
+      .ok()
+      .and_then(|body| body.get("errors").and_then(Value::as_array).cloned())
+      .unwrap_or_default()
+      .iter()
+      .any(|e| e.get("errorType").and_then(Value::as_str) == Some("invalid_grant")),
+    _ => false,
+  }
+}
+
+/// An authorization started by `authorize_url()` that's waiting for its callback.
+struct PendingAuthorization {
+  csrf_token: CsrfToken,
+  pkce_verifier: PkceCodeVerifier,
+}
+
 pub struct OAuthClient {
   client: BasicClient,
   tokens: Option<BasicTokenResponse>,
-  client_id: String,
+  expires_at: Option<DateTime<Utc>>,
+  urls: ServiceUrls,
+  store: TokenStore,
+  pending: Option<PendingAuthorization>,
+  needs_reauthorization: bool,
 }
 
 impl OAuthClient {
-  pub fn for_service(service_name: &str, secrets: &ServiceClient) -> Result<Self> {
-    let urls = URLS.get(service_name).unwrap();
-    let client = BasicClient::new(
+  pub fn for_service(
+    service_name: &str,
+    urls: ServiceUrls,
+    account_id: &str,
+    secrets: &ServiceClient,
+    public_url: &str,
+    project_dirs: &ProjectDirs,
+    vault: SharedVault,
+  ) -> Result<Self> {
+    let mut client = BasicClient::new(
       ClientId::new(secrets.id.to_owned()),
       Some(ClientSecret::new(secrets.secret.to_owned())),
       AuthUrl::new(urls.auth_url.to_owned())?,
       Some(TokenUrl::new(urls.token_url.to_owned())?),
     )
     .set_redirect_uri(RedirectUrl::new(format!(
-      "http://localhost:8000{}", // TODO: hardcoded port
-      urls.redirect_url_path
+      "{}{}",
+      public_url, urls.redirect_url_path
     ))?);
+    if let Some(ref revocation_url) = urls.revocation_url {
+      client = client.set_revocation_uri(RevocationUrl::new(revocation_url.to_owned())?);
+    }
+
+    let store = TokenStore::new(project_dirs, vault, service_name, account_id);
+    let (tokens, expires_at) = match store.load()? {
+      Some(stored) => {
+        info!("Loaded stored {} tokens for {}", service_name, account_id);
+        (Some(stored.tokens), stored.expires_at)
+      }
+      None => (None, None),
+    };
 
-    Ok(Self {
+    let oauth = Self {
       client,
-      client_id: secrets.id.to_owned(),
-      tokens: None,
-    })
+      tokens,
+      expires_at,
+      urls,
+      store,
+      pending: None,
+      needs_reauthorization: false,
+    };
+    oauth.warn_about_missing_scopes(service_name, account_id);
+    Ok(oauth)
   }
 
-  pub fn obtain_tokens(&mut self, auth_code: String) -> Result<()> {
+  /// The requested scopes that the tokens weren't granted, e.g. because they were
+  /// obtained before fitsync started requesting them. Empty if the token response
+  /// didn't list the granted scopes.
+  pub fn missing_scopes(&self) -> Vec<String> {
+    let granted = match self.tokens.as_ref().and_then(|t| t.scopes()) {
+      Some(granted) => granted,
+      None => return vec![],
+    };
+    self
+      .urls
+      .scopes
+      .split(' ')
+      .filter(|scope| !granted.iter().any(|g| g.as_str() == *scope))
+      .map(str::to_owned)
+      .collect()
+  }
+
+  fn warn_about_missing_scopes(&self, service_name: &str, account_id: &str) {
+    let missing = self.missing_scopes();
+    if !missing.is_empty() {
+      warn!(
+        "{} tokens for {} don't grant {}. Authorize again to sync that data.",
+        service_name,
+        account_id,
+        missing.join(", ")
+      );
+    }
+  }
+
+  /// Builds the URL to send the user to for authorization, with a random state and
+  /// PKCE challenge. Only the most recently created URL can be used to obtain tokens.
+  pub fn authorize_url(&mut self) -> Url {
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut request = self
+      .client
+      .authorize_url(CsrfToken::new_random)
+      .add_scopes(
+        self
+          .urls
+          .scopes
+          .split(' ')
+          .map(|s| Scope::new(s.to_owned())),
+      )
+      .set_pkce_challenge(pkce_challenge);
+    for (name, value) in self.urls.extra_auth_params.iter() {
+      request = request.add_extra_param(*name, *value);
+    }
+    let (url, csrf_token) = request.url();
+
+    self.pending = Some(PendingAuthorization {
+      csrf_token,
+      pkce_verifier,
+    });
+
+    url
+  }
+
+  /// Whether `state` belongs to the authorization that's waiting for its callback.
+  pub fn is_pending(&self, state: &str) -> bool {
+    match self.pending {
+      Some(ref pending) => pending.csrf_token.secret() == state,
+      None => false,
+    }
+  }
+
+  /// Exchanges the code from an authorization callback for tokens, as long as the
+  /// callback's state matches the one sent by `authorize_url()`.
+  pub fn obtain_tokens(&mut self, auth_code: String, state: String) -> Result<()> {
+    match self.pending {
+      Some(ref pending) => anyhow::ensure!(
+        pending.csrf_token.secret() == &state,
+        "Authorization state doesn't match. Ignoring possibly forged callback."
+      ),
+      None => {
+        return Err(anyhow!(
+          "No authorization in progress. Call authorize_url() first."
+        ))
+      }
+    }
+    let pending = self.pending.take().unwrap();
+
     let result = self
       .client
       .exchange_code(AuthorizationCode::new(auth_code))
+      .set_pkce_verifier(pending.pkce_verifier)
       .request(http_client)?;
-    self.tokens = Some(result);
+    let vault = self.store.vault.clone();
+    let mut vault = vault.lock().unwrap();
+    self.set_tokens(&mut vault, result)
+  }
+
+  fn set_tokens(&mut self, vault: &mut Vault, mut tokens: BasicTokenResponse) -> Result<()> {
+    // A refresh doesn't necessarily issue a new refresh token, in which case the
+    // existing one remains valid.
+    if tokens.refresh_token().is_none() {
+      let previous = self.tokens.as_ref().and_then(|t| t.refresh_token());
+      tokens.set_refresh_token(previous.cloned());
+    }
+
+    let expires_at = tokens
+      .expires_in()
+      .map(|expires_in| Utc::now() + Duration::from_std(expires_in).unwrap());
+
+    let stored = StoredTokens { tokens, expires_at };
+    self.store.save(vault, &stored)?;
+
+    self.tokens = Some(stored.tokens);
+    self.expires_at = stored.expires_at;
+    self.needs_reauthorization = false;
     Ok(())
   }
 
-  pub fn get_secret(&self) -> Result<String> {
+  /// Forgets the tokens after they've been rejected, so that the user has to
+  /// authorize again.
+  fn require_reauthorization(&mut self, vault: &mut Vault) -> Result<()> {
+    warn!("Tokens are no longer valid. Authorization is required.");
+    self.tokens = None;
+    self.expires_at = None;
+    self.needs_reauthorization = true;
+    self.store.clear(vault)
+  }
+
+  /// Returns the access token, refreshing it first if it's about to expire.
+  pub fn get_secret(&mut self) -> Result<String, TokenError> {
+    let expiring = match self.expires_at {
+      Some(expires_at) => Utc::now() + Duration::minutes(REFRESH_MARGIN_MINUTES) >= expires_at,
+      None => false,
+    };
+    if expiring {
+      info!("Access token is about to expire, refreshing");
+      self.refresh_tokens()?;
+    }
+
     if let Some(ref tokens) = self.tokens {
       Ok(tokens.access_token().secret().to_owned())
     } else {
-      Err(anyhow!("No token retrieved. Call obtain_tokens() first."))
+      Err(TokenError::Unauthorized(
+        "No token retrieved. Call obtain_tokens() first.".to_owned(),
+      ))
     }
   }
 
-  pub fn refresh_tokens(&mut self) -> Result<()> {
-    if let Some(ref tokens) = self.tokens {
-      let result = self
-        .client
-        .exchange_refresh_token(tokens.refresh_token().unwrap())
-        .request(http_client)?;
-      self.tokens = Some(result);
-      Ok(())
-    } else {
-      Err(anyhow!("No token retrieved. Call obtain_tokens() first."))
+  /// Gets a new access token. The tokens are only forgotten if the refresh token is
+  /// rejected; other failures leave them to be tried again.
+  ///
+  /// Each refresh token can only be used once, and another process sharing the vault,
+  /// such as a backfill from the command line, may have used it already. So the vault
+  /// stays locked from checking for newer tokens until the new ones are saved.
+  pub fn refresh_tokens(&mut self) -> Result<(), TokenError> {
+    let vault = self.store.vault.clone();
+    let mut vault = vault.lock().unwrap();
+    vault.locked(|vault| self.refresh_tokens_in(vault))
+  }
+
+  fn refresh_tokens_in(&mut self, vault: &mut Vault) -> Result<(), TokenError> {
+    if let Some(stored) = self.store.load_from(vault)? {
+      let stored_refresh_token = stored.tokens.refresh_token().map(|t| t.secret());
+      let refresh_token = self
+        .tokens
+        .as_ref()
+        .and_then(|t| t.refresh_token())
+        .map(|t| t.secret());
+      if stored_refresh_token != refresh_token {
+        info!("Using tokens refreshed by another process");
+        self.tokens = Some(stored.tokens);
+        self.expires_at = stored.expires_at;
+        self.needs_reauthorization = false;
+        return Ok(());
+      }
+    }
+
+    let refresh_token = match self.tokens {
+      Some(ref tokens) => tokens.refresh_token().cloned(),
+      None => {
+        return Err(TokenError::Unauthorized(
+          "No token retrieved. Call obtain_tokens() first.".to_owned(),
+        ))
+      }
+    };
+
+    let refresh_token = match refresh_token {
+      Some(refresh_token) => refresh_token,
+      None => {
+        self.require_reauthorization(vault)?;
+        return Err(TokenError::Unauthorized(
+          "No refresh token was issued. Authorize again.".to_owned(),
+        ));
+      }
+    };
+
+    match self
+      .client
+      .exchange_refresh_token(&refresh_token)
+      .request(http_client)
+    {
+      Ok(result) => Ok(self.set_tokens(vault, result)?),
+      Err(e) if is_invalid_grant(&e) => {
+        self.require_reauthorization(vault)?;
+        Err(TokenError::Unauthorized(
+          "Refresh token was rejected. Authorize again.".to_owned(),
+        ))
+      }
+      Err(RequestTokenError::Request(HttpClientError::Reqwest(e))) => Err(TokenError::Network(e)),
+      Err(e) => Err(TokenError::Other(e.into())),
+    }
+  }
+
+  /// Revokes the tokens with the service and forgets them. They're forgotten even if
+  /// the service fails to revoke them.
+  pub fn revoke_tokens(&mut self) -> Result<()> {
+    let token: Option<StandardRevocableToken> =
+      self
+        .tokens
+        .as_ref()
+        .map(|tokens| match tokens.refresh_token() {
+          Some(refresh_token) => refresh_token.into(),
+          None => tokens.access_token().into(),
+        });
+
+    self.tokens = None;
+    self.expires_at = None;
+    self.needs_reauthorization = false;
+    self.store.clear(&mut self.store.vault.lock().unwrap())?;
+
+    if let Some(token) = token {
+      self.client.revoke_token(token)?.request(http_client)?;
+      info!("Tokens revoked");
     }
+
+    Ok(())
   }
 
   pub fn has_secret(&self) -> bool {
     self.tokens.is_some()
   }
 
-  pub fn get_client_id(&self) -> String {
-    self.client_id.to_owned()
+  /// Whether previously obtained tokens were rejected and the user has to authorize
+  /// again.
+  pub fn needs_reauthorization(&self) -> bool {
+    self.needs_reauthorization
   }
 }
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+  url
+    .query_pairs()
+    .find(|(key, _)| key == name)
+    .map(|(_, value)| value.into_owned())
+}
+
+/// Authorizes without a browser on this machine or a running web server: prints the
+/// authorize URL, then reads the URL that the browser was redirected to (or just the
+/// code from it) from stdin.
+pub fn authorize_from_terminal(oauth: &mut OAuthClient) -> Result<()> {
+  let url = oauth.authorize_url();
+  let expected_state = query_param(&url, "state").unwrap();
+
+  println!("Open this URL in a browser on any machine and allow access:\n");
+  println!("  {}\n", url);
+  println!("The browser is then redirected to a page that probably won't load. Paste its");
+  println!("full URL (or just the value of its code parameter) here:");
+
+  let mut input = String::new();
+  std::io::stdin().read_line(&mut input)?;
+  let input = input.trim();
+
+  let (code, state) = match Url::parse(input) {
+    Ok(redirected) => (
+      query_param(&redirected, "code").ok_or_else(|| anyhow!("No code parameter in {}", input))?,
+      query_param(&redirected, "state")
+        .ok_or_else(|| anyhow!("No state parameter in {}", input))?,
+    ),
+    Err(_) => (input.trim_end_matches("#_=_").to_owned(), expected_state),
+  };
+
+  oauth.obtain_tokens(code, state)?;
+  println!("Authorized successfully.");
+
+  Ok(())
+}
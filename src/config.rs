@@ -1,7 +1,13 @@
 use std::path::Path;
 
+use crate::account::{AccountId, DEFAULT_ACCOUNT};
 use crate::auth::ServiceClient;
+use crate::fitbit::{DEFAULT_FITBIT_BASE_URL, DEFAULT_FITBIT_UNITS};
+use crate::schedule::Schedule;
+use crate::sheets::{DEFAULT_GOOGLE_OAUTH_BASE_URL, DEFAULT_SHEETS_BASE_URL};
+use crate::vault::SharedVault;
 use anyhow::Result;
+use log::warn;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -10,19 +16,145 @@ pub struct AuthConfig {
   pub google: ServiceClient,
 }
 
+#[derive(Deserialize)]
+pub struct ServerConfig {
+  /// The address the web server binds to.
+  #[serde(default = "ServerConfig::default_address")]
+  pub address: String,
+  #[serde(default = "ServerConfig::default_port")]
+  pub port: u16,
+  /// The URL fitsync is reached at from a browser, if it's not
+  /// `http://localhost:<port>` (e.g. when running behind a reverse proxy).
+  pub public_url: Option<String>,
+}
+
+impl ServerConfig {
+  fn default_address() -> String {
+    "localhost".to_owned()
+  }
+
+  fn default_port() -> u16 {
+    8000
+  }
+
+  /// The base URL for links and OAuth redirects, without a trailing slash.
+  pub fn public_url(&self) -> String {
+    match self.public_url {
+      Some(ref url) => url.trim_end_matches('/').to_owned(),
+      None => format!("http://localhost:{}", self.port),
+    }
+  }
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      address: Self::default_address(),
+      port: Self::default_port(),
+      public_url: None,
+    }
+  }
+}
+
 #[derive(Deserialize)]
 pub struct Config {
   pub auth: AuthConfig,
+  #[serde(default)]
+  pub server: ServerConfig,
+  /// The Fitbit accounts to sync, each authorized separately.
+  #[serde(default = "Config::default_accounts")]
+  pub accounts: Vec<AccountId>,
+  /// The Fitbit API endpoint, which can be pointed at a local server for testing.
+  #[serde(default = "Config::default_fitbit_base_url")]
+  pub fitbit_base_url: String,
+  /// The units of the data: "en_US" for US units, "en_GB" for UK units, any other
+  /// locale for metric, or "profile" for the weight unit in each user's Fitbit
+  /// profile. Changing it changes the units of data synced from then on.
+  #[serde(default = "Config::default_fitbit_units")]
+  pub fitbit_units: String,
+  /// The Google Sheets API endpoint, which can be pointed at a local server for
+  /// testing.
+  #[serde(default = "Config::default_sheets_base_url")]
+  pub sheets_base_url: String,
+  /// Google's OAuth token endpoint, which can be pointed at a local server for
+  /// testing.
+  #[serde(default = "Config::default_google_oauth_base_url")]
+  pub google_oauth_base_url: String,
+  /// When destinations are synced, unless they have their own schedule.
+  #[serde(default)]
+  pub schedule: Schedule,
 }
 
 impl Config {
-  pub fn load() -> Result<Self> {
+  pub fn load(vault: &SharedVault) -> Result<Self> {
     let config_file = Path::new("config.json");
     anyhow::ensure!(
       config_file.exists(),
       "You must create a config.json file containing auth client ids and secrets"
     );
     let text = std::fs::read_to_string(config_file)?;
-    Ok(serde_json::from_str(&text)?)
+    let mut config: Config = serde_json::from_str(&text)?;
+
+    for id in config.accounts.iter() {
+      anyhow::ensure!(
+        !id.is_empty()
+          && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "Account names may only contain letters, digits, '-' and '_': {:?}",
+        id
+      );
+    }
+
+    config.schedule.validate()?;
+
+    read_secret(vault, "fitbit", &mut config.auth.fitbit)?;
+    read_secret(vault, "google", &mut config.auth.google)?;
+
+    Ok(config)
   }
+
+  fn default_accounts() -> Vec<AccountId> {
+    vec![DEFAULT_ACCOUNT.to_owned()]
+  }
+
+  fn default_fitbit_base_url() -> String {
+    DEFAULT_FITBIT_BASE_URL.to_owned()
+  }
+
+  fn default_fitbit_units() -> String {
+    DEFAULT_FITBIT_UNITS.to_owned()
+  }
+
+  fn default_sheets_base_url() -> String {
+    DEFAULT_SHEETS_BASE_URL.to_owned()
+  }
+
+  fn default_google_oauth_base_url() -> String {
+    DEFAULT_GOOGLE_OAUTH_BASE_URL.to_owned()
+  }
+}
+
+/// Reads a client secret from the vault. A secret still present in config.json is
+/// copied into the vault, after which it can be removed from config.json.
+fn read_secret(vault: &SharedVault, service_name: &str, client: &mut ServiceClient) -> Result<()> {
+  let name = format!("client_secret.{}", service_name);
+  let mut vault = vault.lock().unwrap();
+
+  if client.secret.is_empty() {
+    if let Some(secret) = vault.get(&name) {
+      client.secret = secret.to_owned();
+    }
+  } else {
+    if vault.get(&name) != Some(client.secret.as_str()) {
+      vault.set(&name, client.secret.to_owned())?;
+    }
+    warn!(
+      "The {} client secret is in plaintext in config.json. It's now stored in the vault, \
+       so you can remove it from config.json.",
+      service_name
+    );
+  }
+
+  Ok(())
 }
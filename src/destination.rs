@@ -1,32 +1,116 @@
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
   fs::{read_to_string, File},
   io::Write,
   path::PathBuf,
+  sync::Arc,
 };
 
-use anyhow::Result;
-use chrono::{NaiveDateTime, Utc};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 use csv::{Reader, Writer};
 use directories::ProjectDirs;
 use float_cmp::approx_eq;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::fitbit::{FitbitClient, TimeSeriesValue};
+use crate::account::{AccountId, DEFAULT_ACCOUNT};
+use crate::fitbit::{
+  ActivityLog, ActivityResource, BodyType, FitbitClient, HealthMetric, TimeSeriesValue, WeightLog,
+};
+use crate::schedule::Schedule;
+use crate::sheets::SheetsClient;
+
+pub type DestinationId = String;
+
+/// The series written to the configured file or sheet. Other series are written
+/// alongside it.
+static PRIMARY_SERIES: &str = "weight";
+
+/// Rows of a dataset with several fields, e.g. resting heart rate and the time in
+/// each heart rate zone for each day. The first column identifies the row, and is
+/// used to avoid writing the same row twice.
+pub struct Table {
+  /// Names the data, like the series of `append_data`.
+  pub series: String,
+  pub columns: Vec<&'static str>,
+  pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+  pub fn new(series: &str, columns: &[&'static str]) -> Self {
+    Table {
+      series: series.to_owned(),
+      columns: columns.to_vec(),
+      rows: Vec::new(),
+    }
+  }
+}
+
+/// A Fitbit log ID as a table cell. It's written as a string, since a sheet would
+/// show a number that large in scientific notation.
+pub fn log_id_cell(log_id: u64) -> Value {
+  log_id.to_string().into()
+}
 
-type DestinationId = String;
+/// An `f32` as a table cell. `Value::from` would widen it to `f64`, turning 72.3
+/// into 72.30000305175781, so it's parsed from its shortest string form instead.
+/// Whole numbers, such as steps, become integers.
+pub fn f32_cell(value: f32) -> Value {
+  serde_json::from_str(&value.to_string()).unwrap_or(Value::Null)
+}
+
+/// How a value is written to a CSV file: strings without quotes, and missing
+/// values as empty fields.
+fn csv_field(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.to_owned(),
+    Value::Null => String::new(),
+    other => other.to_string(),
+  }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CsvFile {
   path: PathBuf,
 }
 
+impl CsvFile {
+  /// Weight goes to the configured path. Other series go to files next to it with
+  /// the series appended to the name, e.g. `basic_fat.csv` or `basic_steps.csv`.
+  fn path_for(&self, series: &str) -> PathBuf {
+    if series == PRIMARY_SERIES {
+      self.path.clone()
+    } else {
+      self.path_with_suffix(series)
+    }
+  }
+
+  fn path_with_suffix(&self, suffix: &str) -> PathBuf {
+    let stem = self
+      .path
+      .file_stem()
+      .map(|s| s.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    let extension = self
+      .path
+      .extension()
+      .map(|e| e.to_string_lossy().into_owned())
+      .unwrap_or_else(|| "csv".to_owned());
+    self
+      .path
+      .with_file_name(format!("{}_{}.{}", stem, suffix, extension))
+  }
+}
+
 impl DestinationAppender for CsvFile {
-  fn append_data(&self, mut data: Vec<TimeSeriesValue>) -> Result<()> {
+  fn append_data(&self, series: &str, mut data: Vec<TimeSeriesValue>) -> Result<()> {
+    let path = self.path_for(series);
     let mut compressor = TimeSeriesCompressor::new();
 
-    if self.path.exists() {
-      let mut reader = Reader::from_path(&self.path)?;
+    if path.exists() {
+      let mut reader = Reader::from_path(&path)?;
       for rec in reader.deserialize() {
         compressor.values.push(rec?)
       }
@@ -36,41 +120,315 @@ impl DestinationAppender for CsvFile {
 
     compressor.compress();
 
-    let mut writer = Writer::from_path(&self.path)?;
+    let mut writer = Writer::from_path(&path)?;
     for rec in compressor.values {
       writer.serialize(rec)?;
     }
 
     Ok(())
   }
+
+  /// Weight logs go to a file next to the configured path, e.g. `basic_logs.csv`.
+  /// A log that's already in the file is replaced, in case it was edited.
+  fn append_weight_logs(&self, logs: Vec<WeightLog>) -> Result<()> {
+    let path = self.path_with_suffix("logs");
+    let mut by_id = HashMap::new();
+
+    if path.exists() {
+      let mut reader = Reader::from_path(&path)?;
+      for rec in reader.deserialize() {
+        let log: WeightLog = rec?;
+        by_id.insert(log.log_id, log);
+      }
+    }
+
+    for log in logs {
+      by_id.insert(log.log_id, log);
+    }
+
+    let mut logs: Vec<WeightLog> = by_id.into_values().collect();
+    logs.sort_by_key(|log| (log.date_time(), log.log_id));
+
+    let mut writer = Writer::from_path(&path)?;
+    for log in logs {
+      writer.serialize(log)?;
+    }
+
+    Ok(())
+  }
+
+  /// Tables go to files next to the configured path, with rows sorted by their
+  /// first column. A row that's already in the file is replaced, since the data for
+  /// the current day may have changed since it was written.
+  fn append_table(&self, table: Table) -> Result<()> {
+    let path = self.path_with_suffix(&table.series);
+    let mut rows = BTreeMap::new();
+
+    if path.exists() {
+      let mut reader = Reader::from_path(&path)?;
+      for rec in reader.records() {
+        let row: Vec<String> = rec?.iter().map(|field| field.to_owned()).collect();
+        if let Some(key) = row.first() {
+          rows.insert(key.to_owned(), row);
+        }
+      }
+    }
+
+    for row in table.rows {
+      let row: Vec<String> = row.iter().map(csv_field).collect();
+      if let Some(key) = row.first() {
+        rows.insert(key.to_owned(), row);
+      }
+    }
+
+    let mut writer = Writer::from_path(&path)?;
+    writer.write_record(&table.columns)?;
+    for row in rows.values() {
+      writer.write_record(row)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GoogleSheet {
+  spreadsheet_id: String,
+  sheet: String,
+  #[serde(skip)]
+  client: Option<Arc<SheetsClient>>,
+}
+
+impl GoogleSheet {
+  /// Weight goes to the configured sheet. Other series go to sheets with the series
+  /// appended to the name, e.g. `Weight_fat`, which must already exist.
+  fn sheet_for(&self, series: &str) -> String {
+    if series == PRIMARY_SERIES {
+      self.sheet.to_owned()
+    } else {
+      format!("{}_{}", self.sheet, series)
+    }
+  }
+
+  fn client(&self) -> Result<&SheetsClient> {
+    self
+      .client
+      .as_deref()
+      .ok_or_else(|| anyhow!("Google isn't configured in config.json"))
+  }
+}
+
+impl DestinationAppender for GoogleSheet {
+  fn append_data(&self, series: &str, data: Vec<TimeSeriesValue>) -> Result<()> {
+    let client = self.client()?;
+
+    let rows = data
+      .into_iter()
+      .map(|v| vec![v.date_time.to_string().into(), f32_cell(v.value)])
+      .collect();
+    client.append_new_rows(&self.spreadsheet_id, &self.sheet_for(series), rows)
+  }
+
+  /// Weight logs go to a sheet with `_logs` appended to the name, which must already
+  /// exist. Logs whose ID is already in the sheet are skipped.
+  fn append_weight_logs(&self, logs: Vec<WeightLog>) -> Result<()> {
+    let client = self.client()?;
+
+    let rows = logs
+      .into_iter()
+      .map(|log| {
+        vec![
+          log_id_cell(log.log_id),
+          log.date_time().to_string().into(),
+          f32_cell(log.weight),
+          f32_cell(log.bmi),
+          log.fat.map_or(Value::Null, f32_cell),
+          log.source.into(),
+        ]
+      })
+      .collect();
+    client.append_new_rows(&self.spreadsheet_id, &format!("{}_logs", self.sheet), rows)
+  }
+
+  /// Tables go to sheets named like other series, e.g. `Weight_heart_rate`, which
+  /// must already exist. Rows whose first column is already in the sheet are
+  /// replaced, like in a CSV file.
+  fn append_table(&self, table: Table) -> Result<()> {
+    let client = self.client()?;
+    client.update_or_append_rows(
+      &self.spreadsheet_id,
+      &self.sheet_for(&table.series),
+      table.rows,
+    )
+  }
+}
+
+/// A directory that activities are written to as TCX files, which can be imported
+/// into other tools. It only takes activity logs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TcxDirectory {
+  path: PathBuf,
+}
+
+impl DestinationAppender for TcxDirectory {
+  /// Each activity is written to `<date>_<log ID>.tcx`, replacing any earlier copy.
+  fn append_activity_tcx(&self, log: &ActivityLog, tcx: String) -> Result<()> {
+    std::fs::create_dir_all(&self.path)?;
+    let path = self.path.join(format!(
+      "{}_{}.tcx",
+      log.start_time.naive_local().date(),
+      log.log_id
+    ));
+    std::fs::write(path, tcx)?;
+    Ok(())
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DestinationKind {
   CsvFile(CsvFile),
+  GoogleSheet(GoogleSheet),
+  TcxDirectory(TcxDirectory),
 }
 
 impl DestinationKind {
   fn get_appender(&self) -> Box<dyn DestinationAppender> {
     match self {
       Self::CsvFile(file) => Box::new(file.clone()),
+      Self::GoogleSheet(sheet) => Box::new(sheet.clone()),
+      Self::TcxDirectory(directory) => Box::new(directory.clone()),
     }
   }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Destination {
-  id: DestinationId,
+  pub id: DestinationId,
   kind: DestinationKind,
+  /// The account whose data is synced to this destination.
+  #[serde(default = "Destination::default_account")]
+  pub account: AccountId,
+  /// Overrides the schedule in config.json for this destination.
+  pub schedule: Option<Schedule>,
+  /// The first date synced to this destination, if not the date the account was
+  /// created. Data that takes a request per day is only synced for the last 30 or
+  /// 90 days of a sync or backfill.
+  pub start_date: Option<NaiveDate>,
+  /// The body measurements synced to this destination. Defaults to weight for
+  /// destinations that can hold it.
+  #[serde(default)]
+  body_metrics: Option<Vec<BodyType>>,
+  /// How weight is synced, if it's one of `body_metrics`.
+  #[serde(default)]
+  pub weight_mode: WeightMode,
+  /// The daily activity totals synced to this destination.
+  #[serde(default)]
+  pub activity_metrics: Vec<ActivityResource>,
+  /// The metrics measured during sleep synced to this destination, such as SpO2 and
+  /// heart rate variability. Each is written as its own table.
+  #[serde(default)]
+  pub health_metrics: Vec<HealthMetric>,
+  /// Whether to sync the daily resting heart rate and minutes in each zone.
+  #[serde(default)]
+  pub heart_rate: bool,
+  /// Whether to sync heart rate every minute, for at most 30 days. Needs a "Personal" app.
+  #[serde(default)]
+  pub heart_rate_intraday: bool,
+  /// Whether to sync a row for each sleep session.
+  #[serde(default)]
+  pub sleep: bool,
+  /// Whether to also sync each period spent in a sleep stage.
+  #[serde(default)]
+  pub sleep_stages: bool,
+  /// Whether to sync daily calorie, macronutrient and water totals, for at most 90 days.
+  #[serde(default)]
+  pub nutrition: bool,
+  /// Whether to sync each food logged, for at most 90 days.
+  #[serde(default)]
+  pub food_logs: bool,
+  /// Whether to sync each glass of water logged, for at most 90 days.
+  #[serde(default)]
+  pub water_logs: bool,
+  /// Whether to sync each exercise session, as a row or a TCX file in a `TcxDirectory`.
+  #[serde(default)]
+  pub activity_logs: bool,
 }
 
+/// Writes data to a kind of destination. Kinds only implement the data they can
+/// hold; the rest fail.
 trait DestinationAppender {
-  fn append_data(&self, data: Vec<TimeSeriesValue>) -> Result<()>;
+  /// Appends values to `series`, which names the data, e.g. "weight" or "steps".
+  fn append_data(&self, series: &str, _data: Vec<TimeSeriesValue>) -> Result<()> {
+    Err(anyhow!(
+      "Can't write {} to this kind of destination",
+      series
+    ))
+  }
+
+  fn append_weight_logs(&self, _logs: Vec<WeightLog>) -> Result<()> {
+    Err(anyhow!(
+      "Can't write weight logs to this kind of destination"
+    ))
+  }
+
+  fn append_table(&self, table: Table) -> Result<()> {
+    Err(anyhow!(
+      "Can't write {} to this kind of destination",
+      table.series
+    ))
+  }
+
+  fn append_activity_tcx(&self, _log: &ActivityLog, _tcx: String) -> Result<()> {
+    Err(anyhow!("Can't write TCX files to this kind of destination"))
+  }
+}
+
+/// How weight is synced.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightMode {
+  /// One value per day, from the weight time series.
+  #[default]
+  Daily,
+  /// Every measurement, with its time, source and BMI.
+  Logs,
 }
 
 impl Destination {
-  pub fn append_data(&self, data: Vec<TimeSeriesValue>) -> Result<()> {
-    self.kind.get_appender().append_data(data)
+  fn default_account() -> AccountId {
+    DEFAULT_ACCOUNT.to_owned()
+  }
+
+  pub fn body_metrics(&self) -> &[BodyType] {
+    match (&self.body_metrics, &self.kind) {
+      (Some(metrics), _) => metrics,
+      (None, DestinationKind::TcxDirectory(_)) => &[],
+      (None, _) => &[BodyType::Weight],
+    }
+  }
+
+  pub fn append_data(&self, series: &str, data: Vec<TimeSeriesValue>) -> Result<()> {
+    self.kind.get_appender().append_data(series, data)
+  }
+
+  pub fn append_weight_logs(&self, logs: Vec<WeightLog>) -> Result<()> {
+    self.kind.get_appender().append_weight_logs(logs)
+  }
+
+  pub fn append_table(&self, table: Table) -> Result<()> {
+    if table.rows.is_empty() {
+      return Ok(());
+    }
+    self.kind.get_appender().append_table(table)
+  }
+
+  /// Whether activities are written as TCX files rather than rows.
+  pub fn takes_tcx(&self) -> bool {
+    matches!(self.kind, DestinationKind::TcxDirectory(_))
+  }
+
+  pub fn append_activity_tcx(&self, log: &ActivityLog, tcx: String) -> Result<()> {
+    self.kind.get_appender().append_activity_tcx(log, tcx)
   }
 }
 
@@ -87,14 +445,67 @@ impl DestinationConfig {
         kind: DestinationKind::CsvFile(CsvFile {
           path: PathBuf::from("basic.csv"),
         }),
+        account: Destination::default_account(),
+        schedule: None,
+        start_date: None,
+        body_metrics: Some(vec![BodyType::Weight]),
+        weight_mode: WeightMode::default(),
+        activity_metrics: vec![],
+        health_metrics: vec![],
+        heart_rate: false,
+        heart_rate_intraday: false,
+        sleep: false,
+        sleep_stages: false,
+        nutrition: false,
+        food_logs: false,
+        water_logs: false,
+        activity_logs: false,
       }],
     }
   }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The delay before retrying a destination after its first failure. It doubles with
+/// each consecutive failure, up to `MAX_BACKOFF_MINUTES`.
+const BASE_BACKOFF_MINUTES: i64 = 5;
+const MAX_BACKOFF_MINUTES: i64 = 24 * 60;
+
+#[derive(Serialize, Deserialize, Default)]
 struct DestinationCacheData {
   pub last_synced: Option<NaiveDateTime>,
+  #[serde(default)]
+  pub consecutive_failures: u32,
+  #[serde(default)]
+  pub last_error: Option<String>,
+  #[serde(default)]
+  pub last_error_time: Option<NaiveDateTime>,
+}
+
+impl DestinationCacheData {
+  /// The earliest time a failing destination should be retried.
+  fn retry_at(&self) -> Option<NaiveDateTime> {
+    if self.consecutive_failures == 0 {
+      return None;
+    }
+
+    let exponent = (self.consecutive_failures - 1).min(16);
+    let backoff = (BASE_BACKOFF_MINUTES << exponent).min(MAX_BACKOFF_MINUTES);
+    self
+      .last_error_time
+      .map(|time| time + Duration::minutes(backoff))
+  }
+}
+
+/// The sync state of a destination, as reported by the API. Times are in UTC.
+#[derive(Serialize)]
+pub struct DestinationStatus {
+  id: DestinationId,
+  account: AccountId,
+  last_synced: Option<NaiveDateTime>,
+  consecutive_failures: u32,
+  last_error: Option<String>,
+  last_error_time: Option<NaiveDateTime>,
+  retry_at: Option<NaiveDateTime>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -118,11 +529,14 @@ pub struct Destinations {
 }
 
 impl Destinations {
-  pub fn load(project_dirs: &ProjectDirs) -> Result<Destinations> {
+  pub fn load(
+    project_dirs: &ProjectDirs,
+    sheets_client: Option<Arc<SheetsClient>>,
+  ) -> Result<Destinations> {
     let config_file = project_dirs.config_dir().join("destinations.json");
     let cache_file = project_dirs.cache_dir().join("destinations_cache.json");
 
-    Ok(if !config_file.exists() {
+    let mut destinations = if !config_file.exists() {
       Destinations {
         config: DestinationConfig::new(),
         cache: DestinationCache::new(),
@@ -143,35 +557,93 @@ impl Destinations {
         config_file,
         cache_file,
       }
-    })
+    };
+
+    for dest in destinations.config.destinations.iter_mut() {
+      if let Some(ref schedule) = dest.schedule {
+        schedule.validate()?;
+      }
+      if let DestinationKind::GoogleSheet(ref mut sheet) = dest.kind {
+        sheet.client = sheets_client.clone();
+      }
+    }
+
+    Ok(destinations)
   }
 
-  pub fn process<F>(&mut self, processor: F, client: &FitbitClient) -> Result<()>
+  pub fn last_synced(&self, id: &str) -> Option<NaiveDateTime> {
+    if let Some(data) = self.cache.data.get(id) {
+      data.last_synced
+    } else {
+      None
+    }
+  }
+
+  /// The earliest time a failing destination should be retried, in UTC.
+  pub fn retry_at(&self, id: &str) -> Option<NaiveDateTime> {
+    self.cache.data.get(id).and_then(|data| data.retry_at())
+  }
+
+  pub fn statuses(&self) -> Vec<DestinationStatus> {
+    let no_data = DestinationCacheData::default();
+    self
+      .config
+      .destinations
+      .iter()
+      .map(|dest| {
+        let data = self.cache.data.get(&dest.id).unwrap_or(&no_data);
+        DestinationStatus {
+          id: dest.id.to_owned(),
+          account: dest.account.to_owned(),
+          last_synced: data.last_synced,
+          consecutive_failures: data.consecutive_failures,
+          last_error: data.last_error.to_owned(),
+          last_error_time: data.last_error_time,
+          retry_at: data.retry_at(),
+        }
+      })
+      .collect()
+  }
+
+  /// Runs `processor` on each destination that matches `filter`, recording the
+  /// outcome for each. Returns the number of destinations that failed.
+  pub fn process<F, P>(&mut self, filter: F, processor: P, client: &FitbitClient) -> Result<usize>
   where
-    F: Fn(&Destination, &FitbitClient, Option<NaiveDateTime>) -> Result<()>,
+    F: Fn(&Destination) -> bool,
+    P: Fn(&Destination, &FitbitClient, Option<NaiveDateTime>) -> Result<()>,
   {
-    for dest in self.config.destinations.iter() {
-      let last_synced = if let Some(data) = self.cache.data.get(&dest.id) {
-        data.last_synced
-      } else {
-        None
-      };
-
-      processor(dest, client, last_synced)?;
-      let last_synced = Some(Utc::now().naive_local());
-      if let Some(data) = self.cache.data.get_mut(&dest.id) {
-        data.last_synced = last_synced;
-      } else {
-        self
-          .cache
-          .data
-          .insert(dest.id.to_owned(), DestinationCacheData { last_synced });
+    let mut failures = 0;
+
+    for dest in self.config.destinations.iter().filter(|d| filter(d)) {
+      let last_synced = self.last_synced(&dest.id);
+
+      let result = processor(dest, client, last_synced);
+      let now = Utc::now().naive_local();
+      let data = self.cache.data.entry(dest.id.to_owned()).or_default();
+      match result {
+        Ok(()) => {
+          data.last_synced = Some(now);
+          data.consecutive_failures = 0;
+        }
+        Err(e) => {
+          failures += 1;
+          data.consecutive_failures += 1;
+          data.last_error = Some(format!("{:#}", e));
+          data.last_error_time = Some(now);
+          error!(
+            "Sync of {} failed ({} in a row): {:?}",
+            dest.id, data.consecutive_failures, e
+          );
+          if let Some(retry_at) = data.retry_at() {
+            info!("Retrying {} after {} UTC", dest.id, retry_at);
+          }
+        }
       }
     }
 
     self.save_cache()?;
 
-    Ok(())
+    Ok(failures)
   }
 
   fn save_cache(&self) -> Result<()> {
@@ -1,16 +1,25 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
-use anyhow::Context;
 use anyhow::Result;
 use chrono::NaiveDate;
 use chrono::NaiveDateTime;
 use chrono::NaiveTime;
-use reqwest::{blocking::Client, header::AUTHORIZATION};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, FixedOffset, Utc};
+use log::{info, warn};
+use reqwest::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{blocking::Client, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use strum_macros::ToString;
 
-use crate::auth::OAuthClient;
+use crate::auth::{OAuthClient, TokenError};
+
+pub static DEFAULT_FITBIT_BASE_URL: &str = "https://api.fitbit.com";
+pub static DEFAULT_FITBIT_UNITS: &str = "en_US";
+/// The units setting that follows the weight unit in each user's profile.
+pub static PROFILE_UNITS: &str = "profile";
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -20,25 +29,79 @@ pub struct TimeSeriesValue {
   pub value: f32,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct TimeSeriesData {
-  pub body_weight: Vec<TimeSeriesValue>,
-}
-
-#[derive(ToString)]
+#[derive(ToString, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum BodyType {
   Bmi,
   Fat,
   Weight,
 }
 
+impl BodyType {
+  /// The name used for the series in config files and destinations.
+  pub fn name(&self) -> String {
+    self.to_url_parameter()
+  }
+}
+
 impl ToUrlParameter for BodyType {
   fn to_url_parameter(&self) -> String {
     self.to_string().to_lowercase()
   }
 }
 
+/// The daily activity totals that can be requested as a time series.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityResource {
+  Steps,
+  /// In the unit system of the Accept-Language header, e.g. miles for en_US.
+  Distance,
+  Calories,
+  Floors,
+  /// In feet for en_US, and meters otherwise.
+  Elevation,
+  MinutesSedentary,
+  MinutesLightlyActive,
+  MinutesFairlyActive,
+  MinutesVeryActive,
+}
+
+impl ActivityResource {
+  /// The name used for the series in config files and destinations.
+  pub fn name(&self) -> String {
+    match self {
+      Self::Steps => "steps",
+      Self::Distance => "distance",
+      Self::Calories => "calories",
+      Self::Floors => "floors",
+      Self::Elevation => "elevation",
+      Self::MinutesSedentary => "minutes_sedentary",
+      Self::MinutesLightlyActive => "minutes_lightly_active",
+      Self::MinutesFairlyActive => "minutes_fairly_active",
+      Self::MinutesVeryActive => "minutes_very_active",
+    }
+    .to_owned()
+  }
+}
+
+impl ToUrlParameter for ActivityResource {
+  fn to_url_parameter(&self) -> String {
+    match self {
+      Self::Steps => "steps",
+      Self::Distance => "distance",
+      Self::Calories => "calories",
+      Self::Floors => "floors",
+      Self::Elevation => "elevation",
+      Self::MinutesSedentary => "minutesSedentary",
+      Self::MinutesLightlyActive => "minutesLightlyActive",
+      Self::MinutesFairlyActive => "minutesFairlyActive",
+      Self::MinutesVeryActive => "minutesVeryActive",
+    }
+    .to_owned()
+  }
+}
+
 pub enum DateOrToday {
   Today,
   OnDate(NaiveDate),
@@ -90,8 +153,18 @@ trait ToUrlParameter {
 trait ToUrlPath {
   fn to_url_path(&self) -> String;
 
-  fn to_url(&self) -> String {
-    format!("https://api.fitbit.com/1/user/-{}", self.to_url_path())
+  /// The version of the API the endpoint belongs to, e.g. "1.2" for sleep.
+  fn api_version(&self) -> &'static str {
+    "1"
+  }
+
+  fn to_url(&self, base_url: &str) -> String {
+    format!(
+      "{}/{}/user/-{}",
+      base_url,
+      self.api_version(),
+      self.to_url_path()
+    )
   }
 }
 
@@ -143,24 +216,395 @@ impl ToUrlPath for GetBodyRequest {
   }
 }
 
+pub struct GetActivityRequest {
+  resource: ActivityResource,
+  base_date: DateOrToday,
+  end_date: Option<NaiveDate>,
+  time_period: Option<TimePeriod>,
+}
+
+impl GetActivityRequest {
+  pub fn for_date_range(
+    resource: ActivityResource,
+    base_date: DateOrToday,
+    end_date: NaiveDate,
+  ) -> Self {
+    Self {
+      resource,
+      base_date,
+      end_date: Some(end_date),
+      time_period: None,
+    }
+  }
+
+  pub fn for_period(
+    resource: ActivityResource,
+    base_date: DateOrToday,
+    period: TimePeriod,
+  ) -> Self {
+    Self {
+      resource,
+      base_date,
+      end_date: None,
+      time_period: Some(period),
+    }
+  }
+}
+
+impl ToUrlPath for GetActivityRequest {
+  fn to_url_path(&self) -> String {
+    let resource = self.resource.to_url_parameter();
+    let start_date = self.base_date.to_url_parameter();
+
+    let suffix = if let Some(ref time_period) = self.time_period {
+      time_period.to_url_parameter()
+    } else {
+      self.end_date.unwrap().to_url_parameter()
+    };
+
+    format!(
+      "/activities/{}/date/{}/{}.json",
+      resource, start_date, suffix
+    )
+  }
+}
+
+pub struct GetHeartRateRequest {
+  base_date: DateOrToday,
+  end_date: Option<NaiveDate>,
+  time_period: Option<TimePeriod>,
+}
+
+impl GetHeartRateRequest {
+  pub fn for_date_range(base_date: DateOrToday, end_date: NaiveDate) -> Self {
+    Self {
+      base_date,
+      end_date: Some(end_date),
+      time_period: None,
+    }
+  }
+
+  pub fn for_period(base_date: DateOrToday, period: TimePeriod) -> Self {
+    Self {
+      base_date,
+      end_date: None,
+      time_period: Some(period),
+    }
+  }
+}
+
+impl ToUrlPath for GetHeartRateRequest {
+  fn to_url_path(&self) -> String {
+    let start_date = self.base_date.to_url_parameter();
+
+    let suffix = if let Some(ref time_period) = self.time_period {
+      time_period.to_url_parameter()
+    } else {
+      self.end_date.unwrap().to_url_parameter()
+    };
+
+    format!("/activities/heart/date/{}/{}.json", start_date, suffix)
+  }
+}
+
+pub enum DetailLevel {
+  OneSecond,
+  OneMinute,
+}
+
+impl ToUrlParameter for DetailLevel {
+  fn to_url_parameter(&self) -> String {
+    match self {
+      Self::OneSecond => "1sec",
+      Self::OneMinute => "1min",
+    }
+    .to_owned()
+  }
+}
+
+/// Heart rate through a single day. Intraday data is only available to apps of the
+/// "Personal" type.
+pub struct GetHeartRateIntradayRequest {
+  pub date: DateOrToday,
+  pub detail_level: DetailLevel,
+}
+
+impl ToUrlPath for GetHeartRateIntradayRequest {
+  fn to_url_path(&self) -> String {
+    let date = self.date.to_url_parameter();
+    let detail_level = self.detail_level.to_url_parameter();
+
+    format!("/activities/heart/date/{}/1d/{}.json", date, detail_level)
+  }
+}
+
+/// Health metrics that are measured while asleep, one value (or set of values) per
+/// day.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthMetric {
+  /// Blood oxygen saturation.
+  Spo2,
+  /// Heart rate variability.
+  Hrv,
+  BreathingRate,
+  /// Relative to the user's baseline.
+  SkinTemperature,
+  /// VO2 max.
+  CardioScore,
+}
+
+impl HealthMetric {
+  /// The name used for the metric in config files and destinations.
+  pub fn name(&self) -> String {
+    match self {
+      Self::Spo2 => "spo2",
+      Self::Hrv => "hrv",
+      Self::BreathingRate => "breathing_rate",
+      Self::SkinTemperature => "skin_temperature",
+      Self::CardioScore => "cardio_score",
+    }
+    .to_owned()
+  }
+
+  /// The longest date range that can be requested at once, including both ends, as
+  /// documented for each metric's "by interval" endpoint.
+  pub fn max_days(&self) -> i64 {
+    match self {
+      // The SpO2 interval endpoint doesn't document a limit, so stay within the
+      // range of the other sleep metrics.
+      Self::Spo2 => 30,
+      Self::Hrv => 30,
+      Self::BreathingRate => 30,
+      Self::SkinTemperature => 30,
+      Self::CardioScore => 30,
+    }
+  }
+
+  fn url_path(&self) -> &'static str {
+    match self {
+      Self::Spo2 => "/spo2",
+      Self::Hrv => "/hrv",
+      Self::BreathingRate => "/br",
+      Self::SkinTemperature => "/temp/skin",
+      Self::CardioScore => "/cardioscore",
+    }
+  }
+
+  /// The key of the values in the response. SpO2 returns a bare list.
+  fn response_key(&self) -> Option<&'static str> {
+    match self {
+      Self::Spo2 => None,
+      Self::Hrv => Some("hrv"),
+      Self::BreathingRate => Some("br"),
+      Self::SkinTemperature => Some("tempSkin"),
+      Self::CardioScore => Some("cardioScore"),
+    }
+  }
+}
+
+struct GetHealthMetricRequest {
+  metric: HealthMetric,
+  base_date: NaiveDate,
+  end_date: NaiveDate,
+}
+
+impl ToUrlPath for GetHealthMetricRequest {
+  fn to_url_path(&self) -> String {
+    format!(
+      "{}/date/{}/{}.json",
+      self.metric.url_path(),
+      self.base_date.to_url_parameter(),
+      self.end_date.to_url_parameter()
+    )
+  }
+}
+
+/// The foods logged on a day, with the day's totals.
+pub struct GetFoodLogRequest {
+  pub date: DateOrToday,
+}
+
+impl ToUrlPath for GetFoodLogRequest {
+  fn to_url_path(&self) -> String {
+    format!("/foods/log/date/{}.json", self.date.to_url_parameter())
+  }
+}
+
+pub struct GetWaterLogsRequest {
+  pub date: DateOrToday,
+}
+
+impl ToUrlPath for GetWaterLogsRequest {
+  fn to_url_path(&self) -> String {
+    format!(
+      "/foods/log/water/date/{}.json",
+      self.date.to_url_parameter()
+    )
+  }
+}
+
+/// The longest date range that sleep logs can be requested for.
+pub const MAX_SLEEP_LOG_DAYS: i64 = 100;
+/// The most sleep logs returned by a page of the list endpoint.
+const SLEEP_LOG_PAGE_SIZE: u32 = 100;
+
+pub enum GetSleepLogsRequest {
+  /// The logs that end on the dates in the range, which may be at most
+  /// `MAX_SLEEP_LOG_DAYS` long.
+  DateRange(NaiveDate, NaiveDate),
+  /// All logs after a date, oldest first. They're fetched a page at a time.
+  AfterDate(NaiveDate),
+}
+
+impl GetSleepLogsRequest {
+  pub fn for_date_range(base_date: NaiveDate, end_date: NaiveDate) -> Self {
+    Self::DateRange(base_date, end_date)
+  }
+
+  pub fn after_date(date: NaiveDate) -> Self {
+    Self::AfterDate(date)
+  }
+}
+
+impl ToUrlPath for GetSleepLogsRequest {
+  fn to_url_path(&self) -> String {
+    match self {
+      Self::DateRange(base_date, end_date) => format!(
+        "/sleep/date/{}/{}.json",
+        base_date.to_url_parameter(),
+        end_date.to_url_parameter()
+      ),
+      Self::AfterDate(date) => format!(
+        "/sleep/list.json?afterDate={}&sort=asc&offset=0&limit={}",
+        date.to_url_parameter(),
+        SLEEP_LOG_PAGE_SIZE
+      ),
+    }
+  }
+
+  fn api_version(&self) -> &'static str {
+    "1.2"
+  }
+}
+
+/// The most activity logs returned by a page of the list endpoint.
+const ACTIVITY_LOG_PAGE_SIZE: u32 = 100;
+
+pub enum GetActivityLogsRequest {
+  /// All logs that start after a date, oldest first.
+  AfterDate(NaiveDate),
+  /// All logs that start before a date, newest first.
+  BeforeDate(NaiveDate),
+}
+
+impl GetActivityLogsRequest {
+  pub fn after_date(date: NaiveDate) -> Self {
+    Self::AfterDate(date)
+  }
+
+  pub fn before_date(date: NaiveDate) -> Self {
+    Self::BeforeDate(date)
+  }
+}
+
+impl ToUrlPath for GetActivityLogsRequest {
+  fn to_url_path(&self) -> String {
+    // The API only allows ascending order with afterDate, and descending with
+    // beforeDate.
+    let (cursor, date, sort) = match self {
+      Self::AfterDate(date) => ("afterDate", date, "asc"),
+      Self::BeforeDate(date) => ("beforeDate", date, "desc"),
+    };
+    format!(
+      "/activities/list.json?{}={}&sort={}&offset=0&limit={}",
+      cursor,
+      date.to_url_parameter(),
+      sort,
+      ACTIVITY_LOG_PAGE_SIZE
+    )
+  }
+}
+
+/// The GPS track, heart rate and laps of a logged activity, as a Training Center
+/// XML file.
+pub struct GetActivityTcxRequest {
+  pub log_id: u64,
+}
+
+impl ToUrlPath for GetActivityTcxRequest {
+  fn to_url_path(&self) -> String {
+    format!("/activities/{}.tcx", self.log_id)
+  }
+}
+
+struct GetProfileRequest;
+
+impl ToUrlPath for GetProfileRequest {
+  fn to_url_path(&self) -> String {
+    "/profile.json".to_owned()
+  }
+}
+
+struct GetDevicesRequest;
+
+impl ToUrlPath for GetDevicesRequest {
+  fn to_url_path(&self) -> String {
+    "/devices.json".to_owned()
+  }
+}
+
+/// The longest date range that weight logs can be requested for.
+pub const MAX_WEIGHT_LOG_DAYS: i64 = 31;
+
 pub struct GetWeightLogsRequest {
-  pub base_date: NaiveDate,
-  pub time_period: TimePeriod,
+  base_date: NaiveDate,
+  end_date: Option<NaiveDate>,
+  time_period: Option<TimePeriod>,
+}
+
+impl GetWeightLogsRequest {
+  /// The range may be at most `MAX_WEIGHT_LOG_DAYS` long, including both ends.
+  pub fn for_date_range(base_date: NaiveDate, end_date: NaiveDate) -> Self {
+    Self {
+      base_date,
+      end_date: Some(end_date),
+      time_period: None,
+    }
+  }
+
+  /// Only periods of up to a month are allowed.
+  pub fn for_period(base_date: NaiveDate, period: TimePeriod) -> Self {
+    Self {
+      base_date,
+      end_date: None,
+      time_period: Some(period),
+    }
+  }
 }
 
 impl ToUrlPath for GetWeightLogsRequest {
   fn to_url_path(&self) -> String {
     let base_date = self.base_date.to_url_parameter();
-    let period = self.time_period.to_url_parameter();
 
-    format!("/body/log/weight/date/{}/{}.json", base_date, period)
+    let suffix = if let Some(ref time_period) = self.time_period {
+      time_period.to_url_parameter()
+    } else {
+      self.end_date.unwrap().to_url_parameter()
+    };
+
+    format!("/body/log/weight/date/{}/{}.json", base_date, suffix)
   }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 enum ErrorType {
   ExpiredToken,
+  InvalidToken,
+  InvalidClient,
+  InsufficientScope,
+  InsufficientPermissions,
   #[serde(other)]
   Unknown,
 }
@@ -173,14 +617,164 @@ struct ApiError {
 }
 
 #[derive(Deserialize, Debug)]
+struct ErrorResponse {
+  errors: Vec<ApiError>,
+}
+
+/// Why a request to the Fitbit API failed.
+#[derive(Debug)]
+pub enum FitbitError {
+  /// The access token has expired and needs to be refreshed.
+  ExpiredToken,
+  /// The access token was rejected before it expired, e.g. because it was revoked.
+  Unauthorized(String),
+  /// The refresh token was rejected, or there are no tokens. The user has to
+  /// authorize again.
+  InvalidToken(String),
+  /// Refreshing the access token failed for another reason, such as an error from
+  /// the token endpoint. The tokens are kept.
+  RefreshFailed(String),
+  /// The user didn't grant the scope needed for the request.
+  InsufficientScope(String),
+  /// The rate limit was exceeded, even after waiting for it to reset.
+  RateLimited {
+    retry_after: Duration,
+  },
+  NotFound(String),
+  ServerError {
+    status: StatusCode,
+    message: String,
+  },
+  /// Any other unsuccessful response, such as a validation error.
+  RequestFailed {
+    status: StatusCode,
+    message: String,
+  },
+  Network(reqwest::Error),
+  Parse(String),
+}
+
+impl FitbitError {
+  fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+    let errors = serde_json::from_str::<ErrorResponse>(body)
+      .map(|r| r.errors)
+      .unwrap_or_default();
+    let has_error = |error_type: ErrorType| errors.iter().any(|e| e.error_type == error_type);
+    let message = if errors.is_empty() {
+      body.to_owned()
+    } else {
+      errors
+        .iter()
+        .map(|e| e.message.as_str())
+        .collect::<Vec<_>>()
+        .join("; ")
+    };
+
+    match status {
+      StatusCode::UNAUTHORIZED if has_error(ErrorType::ExpiredToken) => FitbitError::ExpiredToken,
+      StatusCode::UNAUTHORIZED => FitbitError::Unauthorized(message),
+      StatusCode::FORBIDDEN => FitbitError::InsufficientScope(message),
+      StatusCode::NOT_FOUND => FitbitError::NotFound(message),
+      StatusCode::TOO_MANY_REQUESTS => FitbitError::RateLimited {
+        retry_after: retry_after(headers),
+      },
+      s if s.is_server_error() => FitbitError::ServerError { status, message },
+      _ if has_error(ErrorType::InsufficientScope)
+        || has_error(ErrorType::InsufficientPermissions) =>
+      {
+        FitbitError::InsufficientScope(message)
+      }
+      _ => FitbitError::RequestFailed { status, message },
+    }
+  }
+
+  /// Whether the same request might succeed if it's tried again later.
+  pub fn is_retryable(&self) -> bool {
+    matches!(
+      self,
+      FitbitError::RateLimited { .. }
+        | FitbitError::ServerError { .. }
+        | FitbitError::Network(_)
+        | FitbitError::RefreshFailed(_)
+    )
+  }
+
+  /// Whether the user has to authorize again before requests can succeed. The
+  /// tokens have already been forgotten.
+  pub fn needs_reauthorization(&self) -> bool {
+    matches!(self, FitbitError::InvalidToken(_))
+  }
+}
+
+impl fmt::Display for FitbitError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FitbitError::ExpiredToken => write!(f, "Access token expired"),
+      FitbitError::Unauthorized(message) => write!(f, "Access token rejected: {}", message),
+      FitbitError::InvalidToken(message) => write!(f, "Invalid token: {}", message),
+      FitbitError::RefreshFailed(message) => write!(f, "Couldn't refresh token: {}", message),
+      FitbitError::InsufficientScope(message) => write!(
+        f,
+        "Insufficient scope: {}. Authorize fitsync again to grant access to this data.",
+        message
+      ),
+      FitbitError::RateLimited { retry_after } => write!(
+        f,
+        "Rate limit exceeded, retry in {}s",
+        retry_after.as_secs()
+      ),
+      FitbitError::NotFound(message) => write!(f, "Not found: {}", message),
+      FitbitError::ServerError { status, message } => {
+        write!(f, "Server error {}: {}", status, message)
+      }
+      FitbitError::RequestFailed { status, message } => {
+        write!(f, "Request failed with {}: {}", status, message)
+      }
+      FitbitError::Network(e) => write!(f, "Network error: {}", e),
+      FitbitError::Parse(message) => write!(f, "Couldn't parse response: {}", message),
+    }
+  }
+}
+
+impl std::error::Error for FitbitError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      FitbitError::Network(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<reqwest::Error> for FitbitError {
+  fn from(e: reqwest::Error) -> Self {
+    FitbitError::Network(e)
+  }
+}
+
+impl From<TokenError> for FitbitError {
+  fn from(e: TokenError) -> Self {
+    match e {
+      TokenError::Unauthorized(message) => FitbitError::InvalidToken(message),
+      TokenError::Network(e) => FitbitError::Network(e),
+      TokenError::Other(e) => FitbitError::RefreshFailed(format!("{:#}", e)),
+    }
+  }
+}
+
+/// A single weight measurement. The fields are in the order they're written to
+/// destinations, with the ID that identifies the log first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WeightLog {
-  bmi: f32,
-  weight: f32,
-  source: String,
   #[serde(rename = "logId")]
-  log_id: u64,
-  date: NaiveDate,
-  time: NaiveTime,
+  pub log_id: u64,
+  pub date: NaiveDate,
+  pub time: NaiveTime,
+  pub weight: f32,
+  pub bmi: f32,
+  pub fat: Option<f32>,
+  /// Where the measurement came from, e.g. "Aria" for a scale or "API" or "Web" for
+  /// a manual entry.
+  pub source: String,
 }
 
 impl WeightLog {
@@ -189,88 +783,808 @@ impl WeightLog {
   }
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateZone {
+  /// "Out of Range", "Fat Burn", "Cardio" or "Peak".
+  pub name: String,
+  pub min: u32,
+  pub max: u32,
+  /// Missing on days without heart rate data.
+  pub minutes: Option<u32>,
+  pub calories_out: Option<f32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateSummary {
+  /// Missing on days without enough heart rate data to calculate it.
+  pub resting_heart_rate: Option<u32>,
+  #[serde(default)]
+  pub heart_rate_zones: Vec<HeartRateZone>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateDay {
+  pub date_time: NaiveDate,
+  pub value: HeartRateSummary,
+}
+
+impl HeartRateDay {
+  /// The minutes spent in the zone called `name`, if known.
+  pub fn zone_minutes(&self, name: &str) -> Option<u32> {
+    self
+      .value
+      .heart_rate_zones
+      .iter()
+      .find(|zone| zone.name == name)
+      .and_then(|zone| zone.minutes)
+  }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IntradayHeartRate {
+  pub time: NaiveTime,
+  /// In beats per minute.
+  pub value: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct IntradayDataset {
+  dataset: Vec<IntradayHeartRate>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HeartRateResponse {
+  #[serde(rename = "activities-heart")]
+  days: Vec<HeartRateDay>,
+  #[serde(rename = "activities-heart-intraday")]
+  intraday: Option<IntradayDataset>,
+}
+
+/// A day's value of a health metric.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthMetricDay<T> {
+  pub date_time: NaiveDate,
+  pub value: T,
+}
+
+/// Blood oxygen saturation as a percentage.
+#[derive(Deserialize, Debug)]
+pub struct Spo2Value {
+  pub avg: f64,
+  pub min: f64,
+  pub max: f64,
+}
+
+/// Heart rate variability, as the root mean square of successive differences between
+/// heart beats in milliseconds.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HrvValue {
+  pub daily_rmssd: f64,
+  pub deep_rmssd: f64,
+}
+
+/// Breaths per minute.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BreathingRateValue {
+  pub breathing_rate: f64,
+}
+
+/// The difference from the user's baseline, in degrees of the unit system of the
+/// Accept-Language header.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SkinTemperatureValue {
+  pub nightly_relative: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CardioScoreValue {
+  /// In mL/kg/min. A range such as "44-48" unless the user's weight is known.
+  pub vo2_max: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum HealthMetricResponse<T> {
+  List(Vec<HealthMetricDay<T>>),
+  Keyed(HashMap<String, Vec<HealthMetricDay<T>>>),
+}
+
+/// Nutrients in the units of the Accept-Language header. For en_US, energy is in
+/// calories, sodium in milligrams and the rest in grams.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NutritionalValues {
+  pub calories: f64,
+  pub carbs: f64,
+  pub fat: f64,
+  pub fiber: f64,
+  pub protein: f64,
+  pub sodium: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FoodUnit {
+  pub name: String,
+  pub plural: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoggedFood {
+  pub name: String,
+  #[serde(default)]
+  pub brand: String,
+  pub amount: f64,
+  pub unit: Option<FoodUnit>,
+  pub meal_type_id: u32,
+}
+
+impl LoggedFood {
+  pub fn meal(&self) -> &'static str {
+    match self.meal_type_id {
+      1 => "Breakfast",
+      2 => "Morning Snack",
+      3 => "Lunch",
+      4 => "Afternoon Snack",
+      5 => "Dinner",
+      _ => "Anytime",
+    }
+  }
+
+  /// The unit, pluralized to suit the amount.
+  pub fn unit_name(&self) -> Option<&str> {
+    self.unit.as_ref().map(|unit| {
+      if (self.amount - 1.0).abs() < f64::EPSILON {
+        unit.name.as_str()
+      } else {
+        unit.plural.as_str()
+      }
+    })
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FoodLogEntry {
+  pub log_id: u64,
+  pub log_date: NaiveDate,
+  pub logged_food: LoggedFood,
+  #[serde(default)]
+  pub nutritional_values: NutritionalValues,
+}
+
+/// The day's totals.
+#[derive(Deserialize, Debug)]
+pub struct FoodLogSummary {
+  #[serde(flatten)]
+  pub nutrients: NutritionalValues,
+  /// All the water logged that day, in the same units as `WaterLog::amount`.
+  #[serde(default)]
+  pub water: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FoodLogDay {
+  pub foods: Vec<FoodLogEntry>,
+  pub summary: FoodLogSummary,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WaterLog {
+  pub log_id: u64,
+  /// In fluid ounces for en_US, and milliliters otherwise.
+  pub amount: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct WaterLogsResponse {
+  water: Vec<WaterLog>,
+}
+
+/// A period spent in one sleep stage (or level, for classic logs).
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepStage {
+  pub date_time: NaiveDateTime,
+  /// "deep", "light", "rem" or "wake" for stages logs, and "asleep", "restless" or
+  /// "awake" for classic logs.
+  pub level: String,
+  pub seconds: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLevelSummary {
+  pub count: Option<u32>,
+  pub minutes: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLevels {
+  #[serde(default)]
+  pub data: Vec<SleepStage>,
+  /// Keyed by level.
+  #[serde(default)]
+  pub summary: HashMap<String, SleepLevelSummary>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLog {
+  pub log_id: u64,
+  pub date_of_sleep: NaiveDate,
+  pub start_time: NaiveDateTime,
+  pub end_time: NaiveDateTime,
+  pub is_main_sleep: bool,
+  /// "stages", or "classic" when there wasn't enough heart rate data to tell the
+  /// stages apart.
+  #[serde(rename = "type")]
+  pub log_type: String,
+  pub efficiency: u32,
+  pub minutes_asleep: u32,
+  pub minutes_awake: u32,
+  pub minutes_to_fall_asleep: u32,
+  pub time_in_bed: u32,
+  pub levels: SleepLevels,
+}
+
+impl SleepLog {
+  /// The minutes spent in `level`, if the log has that level.
+  pub fn level_minutes(&self, level: &str) -> Option<u32> {
+    self
+      .levels
+      .summary
+      .get(level)
+      .map(|summary| summary.minutes)
+  }
+}
+
+/// An exercise session, either logged by the user or recorded by the tracker.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityLog {
+  pub log_id: u64,
+  pub activity_name: String,
+  pub activity_type_id: u64,
+  /// "auto_detected", "manual", "mobile_run" or "tracker".
+  pub log_type: String,
+  pub start_time: DateTime<FixedOffset>,
+  /// In milliseconds, including pauses.
+  pub duration: u64,
+  /// In milliseconds, excluding pauses.
+  pub active_duration: u64,
+  pub average_heart_rate: Option<u32>,
+  pub calories: u32,
+  /// In `distance_unit`, which follows the Accept-Language header.
+  pub distance: Option<f64>,
+  pub distance_unit: Option<String>,
+  pub steps: Option<u32>,
+  /// Where to download the activity as a TCX file. Only set for activities that
+  /// were recorded with heart rate or GPS.
+  pub tcx_link: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ActivityLogsResponse {
+  activities: Vec<ActivityLog>,
+  pagination: Option<Pagination>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Pagination {
+  /// The URL of the next page, or empty on the last page.
+  #[serde(default)]
+  next: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SleepResponse {
+  sleep: Vec<SleepLog>,
+  pagination: Option<Pagination>,
+}
+
+/// The parts of the user's profile that affect syncing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+  /// An IANA timezone name, e.g. "America/Los_Angeles". Dates in the API are in this
+  /// timezone.
+  pub timezone: String,
+  /// The timezone's current offset.
+  #[serde(rename = "offsetFromUTCMillis")]
+  pub offset_from_utc_millis: i64,
+  /// Sent as the Accept-Locale header, e.g. "en_US".
+  pub locale: String,
+  /// The unit system weights are shown in on the Fitbit app, e.g. "METRIC" or
+  /// "en_US". It's sent as the Accept-Language header, which sets the units in
+  /// responses, if config.json asks for the profile's units.
+  pub weight_unit: String,
+  pub member_since: NaiveDate,
+}
+
+impl Profile {
+  /// Converts a UTC time to the user's timezone.
+  pub fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+    utc + chrono::Duration::milliseconds(self.offset_from_utc_millis)
+  }
+
+  /// The current date in the user's timezone.
+  pub fn today(&self) -> NaiveDate {
+    self.to_local(Utc::now().naive_utc()).date()
+  }
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileResponse {
+  user: Profile,
+}
+
+/// A tracker, watch or scale paired with the account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+  pub id: String,
+  /// The model, e.g. "Charge 5" or "Aria".
+  pub device_version: String,
+  /// "TRACKER" or "SCALE".
+  #[serde(rename = "type")]
+  pub device_type: String,
+  /// "High", "Medium", "Low" or "Empty".
+  pub battery: String,
+  pub battery_level: Option<u32>,
+  /// In the user's timezone.
+  pub last_sync_time: Option<NaiveDateTime>,
+}
+
 #[derive(Deserialize, Debug)]
 struct GenericResponse {
-  success: Option<bool>,
-  errors: Option<Vec<ApiError>>,
-  #[serde(rename = "body-weight")]
-  body_weight: Option<Vec<TimeSeriesValue>>,
   weight: Option<Vec<WeightLog>>,
 }
 
-impl GenericResponse {
-  fn has_expired_token(&self) -> bool {
-    if let Some(ref errors) = self.errors {
-      errors
-        .iter()
-        .any(|x| matches!(x.error_type, ErrorType::ExpiredToken))
-    } else {
-      false
+/// When the remaining quota drops to this, requests wait for the quota to reset.
+const LOW_QUOTA: u32 = 3;
+/// How many times a request that was rejected for exceeding the rate limit is retried.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// How long to wait after a 429 that doesn't say when to retry.
+const DEFAULT_RATE_LIMIT_WAIT_SECS: u64 = 60;
+
+/// The request quota reported by the `Fitbit-Rate-Limit-*` response headers.
+#[derive(Serialize, Debug, Clone)]
+pub struct RateLimit {
+  pub limit: u32,
+  pub remaining: u32,
+  pub reset_at: DateTime<Utc>,
+}
+
+impl RateLimit {
+  fn from_headers(headers: &HeaderMap) -> Option<Self> {
+    let header = |name: &str| -> Option<u32> { headers.get(name)?.to_str().ok()?.parse().ok() };
+
+    Some(RateLimit {
+      limit: header("fitbit-rate-limit-limit")?,
+      remaining: header("fitbit-rate-limit-remaining")?,
+      reset_at: Utc::now() + chrono::Duration::seconds(header("fitbit-rate-limit-reset")? as i64),
+    })
+  }
+
+  /// How long to wait before making another request, if the quota is nearly used up.
+  fn wait_time(&self) -> Option<Duration> {
+    if self.remaining > LOW_QUOTA {
+      return None;
     }
+    (self.reset_at - Utc::now()).to_std().ok()
   }
 }
 
+/// How long a 429 response asks us to wait before retrying.
+fn retry_after(headers: &HeaderMap) -> Duration {
+  let seconds = [RETRY_AFTER.as_str(), "fitbit-rate-limit-reset"]
+    .iter()
+    .filter_map(|name| headers.get(*name)?.to_str().ok()?.parse().ok())
+    .next()
+    .unwrap_or(DEFAULT_RATE_LIMIT_WAIT_SECS);
+  Duration::from_secs(seconds)
+}
+
 pub trait TokenProvider {
   fn get_token(&self) -> Result<String>;
   fn refresh_token(&self) -> Result<String>;
 }
 
+/// How long the profile is kept before it's fetched again, so that a change of
+/// timezone or daylight saving time is picked up.
+const PROFILE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Sync times and battery levels change often, so devices are only kept briefly.
+const DEVICES_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// A response that's reused until it's `max_age` old.
+struct Cached<T> {
+  value: Mutex<Option<(T, Instant)>>,
+  max_age: Duration,
+}
+
+impl<T: Clone> Cached<T> {
+  fn new(max_age: Duration) -> Self {
+    Cached {
+      value: Mutex::new(None),
+      max_age,
+    }
+  }
+
+  fn get_or_fetch<F>(&self, fetch: F) -> Result<T, FitbitError>
+  where
+    F: FnOnce() -> Result<T, FitbitError>,
+  {
+    let mut value = self.value.lock().unwrap();
+    if let Some((ref cached, fetched_at)) = *value {
+      if fetched_at.elapsed() < self.max_age {
+        return Ok(cached.clone());
+      }
+    }
+
+    let fetched = fetch()?;
+    *value = Some((fetched.clone(), Instant::now()));
+    Ok(fetched)
+  }
+}
+
 pub struct FitbitClient {
   pub oauth: Mutex<OAuthClient>,
   http_client: Client,
+  base_url: String,
+  units: String,
+  rate_limit: Mutex<Option<RateLimit>>,
+  profile: Cached<Profile>,
+  devices: Cached<Vec<Device>>,
 }
 
 impl FitbitClient {
-  pub fn new(oauth: OAuthClient) -> Self {
+  /// `units` is sent as the Accept-Language header, unless it's `PROFILE_UNITS`.
+  pub fn new(oauth: OAuthClient, base_url: &str, units: &str) -> Self {
     FitbitClient {
       oauth: Mutex::new(oauth),
       http_client: reqwest::blocking::Client::new(),
+      base_url: base_url.trim_end_matches('/').to_owned(),
+      units: units.to_owned(),
+      rate_limit: Mutex::new(None),
+      profile: Cached::new(PROFILE_MAX_AGE),
+      devices: Cached::new(DEVICES_MAX_AGE),
     }
   }
 
-  fn make_request_with_secret(&self, url: &str, secret: &str) -> Result<GenericResponse> {
-    let res = self
-      .http_client
-      .get(url)
-      .header(AUTHORIZATION, format!("Bearer {}", secret))
-      .header("Accept-Language", "en_US")
-      .send()?;
-
-    let text = res.text()?;
-    serde_json::from_str(&text).with_context(|| format!("Couldn't parse: {}", text))
+  /// The quota reported by the most recent response, if any.
+  pub fn rate_limit(&self) -> Option<RateLimit> {
+    self.rate_limit.lock().unwrap().clone()
   }
 
-  fn make_request(&self, url: String) -> Result<GenericResponse> {
-    // TODO: Probably don't need to lock this for the whole duration of the request.
-    let mut unlocked_oauth = self.oauth.lock().unwrap();
-    let secret = unlocked_oauth.get_secret()?;
-    let result = self.make_request_with_secret(&url, &secret)?;
+  /// Pauses until the quota resets if it's nearly used up.
+  fn wait_for_quota(&self) {
+    let wait_time = self
+      .rate_limit
+      .lock()
+      .unwrap()
+      .as_ref()
+      .and_then(|r| r.wait_time());
+
+    if let Some(wait_time) = wait_time {
+      info!(
+        "Fitbit rate limit nearly reached, pausing for {}s until it resets",
+        wait_time.as_secs()
+      );
+      std::thread::sleep(wait_time);
+      *self.rate_limit.lock().unwrap() = None;
+    }
+  }
 
-    Ok(if !result.has_expired_token() {
-      result
+  /// The headers that set the units and locale of responses. Units other than
+  /// "en_US" and "en_GB" are metric.
+  fn locale_headers(&self) -> Result<Vec<(&'static str, String)>, FitbitError> {
+    let profile = self.profile()?;
+    let units = if self.units == PROFILE_UNITS {
+      profile.weight_unit
     } else {
-      unlocked_oauth.refresh_tokens()?;
-      let new_secret = unlocked_oauth.get_secret()?;
-      self.make_request_with_secret(&url, &new_secret)?
+      self.units.clone()
+    };
+    Ok(vec![
+      ("Accept-Language", units),
+      ("Accept-Locale", profile.locale),
+    ])
+  }
+
+  fn get_text_with_secret(
+    &self,
+    url: &str,
+    secret: &str,
+    headers: &[(&'static str, String)],
+  ) -> Result<String, FitbitError> {
+    let mut retries = 0;
+    loop {
+      self.wait_for_quota();
+
+      let mut request = self
+        .http_client
+        .get(url)
+        .header(AUTHORIZATION, format!("Bearer {}", secret));
+      for (name, value) in headers {
+        request = request.header(*name, value);
+      }
+      let res = request.send()?;
+
+      if let Some(rate_limit) = RateLimit::from_headers(res.headers()) {
+        *self.rate_limit.lock().unwrap() = Some(rate_limit);
+      }
+
+      let status = res.status();
+      if status == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RATE_LIMIT_RETRIES {
+        retries += 1;
+        let wait_time = retry_after(res.headers());
+        warn!(
+          "Fitbit rate limit exceeded, retrying in {}s",
+          wait_time.as_secs()
+        );
+        std::thread::sleep(wait_time);
+        continue;
+      }
+
+      let headers = res.headers().clone();
+      let text = res.text()?;
+      if !status.is_success() {
+        return Err(FitbitError::from_response(status, &headers, &text));
+      }
+      return Ok(text);
+    }
+  }
+
+  /// Makes a request for a response that isn't JSON, refreshing the token first if
+  /// it's expired. The tokens are only locked while they're read or refreshed, not
+  /// while waiting for a response or for the rate limit to reset.
+  fn get_text(&self, url: String) -> Result<String, FitbitError> {
+    let headers = self.locale_headers()?;
+    self.get_text_with_headers(url, &headers)
+  }
+
+  fn get_text_with_headers(
+    &self,
+    url: String,
+    headers: &[(&'static str, String)],
+  ) -> Result<String, FitbitError> {
+    let secret = self.oauth.lock().unwrap().get_secret()?;
+
+    match self.get_text_with_secret(&url, &secret, headers) {
+      // A token that was rejected before it expired may still be refreshable.
+      Err(FitbitError::ExpiredToken) | Err(FitbitError::Unauthorized(_)) => {
+        let new_secret = {
+          let mut oauth = self.oauth.lock().unwrap();
+          // Another request may have refreshed the tokens in the meantime, and a
+          // refresh token can only be used once.
+          if oauth.get_secret()? == secret {
+            oauth.refresh_tokens()?;
+          }
+          oauth.get_secret()?
+        };
+        self.get_text_with_secret(&url, &new_secret, headers)
+      }
+      result => result,
+    }
+  }
+
+  fn make_request<T: DeserializeOwned>(&self, url: String) -> Result<T, FitbitError> {
+    let text = self.get_text(url)?;
+    serde_json::from_str(&text).map_err(|e| FitbitError::Parse(format!("{}: {}", e, text)))
+  }
+
+  /// Fetches `url` and each page after it, using `split` to separate the items in a
+  /// page from its link to the next.
+  fn get_all_pages<R, T, F>(&self, mut url: String, split: F) -> Result<Vec<T>, FitbitError>
+  where
+    R: DeserializeOwned,
+    F: Fn(R) -> (Vec<T>, Option<Pagination>),
+  {
+    let mut items = Vec::new();
+    loop {
+      let (page, pagination) = split(self.make_request(url)?);
+      let last_page = page.is_empty();
+      items.extend(page);
+
+      match pagination {
+        Some(pagination) if !pagination.next.is_empty() && !last_page => {
+          // The next page is relative to the base URL we're using, which might not
+          // be the one the API reports.
+          let next = Url::parse(&pagination.next)
+            .map_err(|e| FitbitError::Parse(format!("{}: {}", e, pagination.next)))?;
+          url = format!(
+            "{}{}?{}",
+            self.base_url,
+            next.path(),
+            next.query().unwrap_or_default()
+          );
+        }
+        _ => return Ok(items),
+      }
+    }
+  }
+
+  pub fn get_body(&self, request: GetBodyRequest) -> Result<Vec<TimeSeriesValue>, FitbitError> {
+    // The values are keyed by the body type, e.g. "body-weight" or "body-fat".
+    let key = format!("body-{}", request.body_type.to_url_parameter());
+    let mut response: HashMap<String, Vec<TimeSeriesValue>> =
+      self.make_request(request.to_url(&self.base_url))?;
+
+    response
+      .remove(&key)
+      .ok_or_else(|| FitbitError::Parse(format!("No {} in response: {:?}", key, response)))
+  }
+
+  pub fn get_activity(
+    &self,
+    request: GetActivityRequest,
+  ) -> Result<Vec<TimeSeriesValue>, FitbitError> {
+    // The values are keyed by the resource, e.g. "activities-steps".
+    let key = format!("activities-{}", request.resource.to_url_parameter());
+    let mut response: HashMap<String, Vec<TimeSeriesValue>> =
+      self.make_request(request.to_url(&self.base_url))?;
+
+    response
+      .remove(&key)
+      .ok_or_else(|| FitbitError::Parse(format!("No {} in response: {:?}", key, response)))
+  }
+
+  pub fn get_heart_rate(
+    &self,
+    request: GetHeartRateRequest,
+  ) -> Result<Vec<HeartRateDay>, FitbitError> {
+    let response: HeartRateResponse = self.make_request(request.to_url(&self.base_url))?;
+    Ok(response.days)
+  }
+
+  pub fn get_heart_rate_intraday(
+    &self,
+    request: GetHeartRateIntradayRequest,
+  ) -> Result<Vec<IntradayHeartRate>, FitbitError> {
+    let response: HeartRateResponse = self.make_request(request.to_url(&self.base_url))?;
+    response.intraday.map(|i| i.dataset).ok_or_else(|| {
+      FitbitError::Parse(
+        "No intraday heart rate in response. Is the app of the \"Personal\" type?".to_owned(),
+      )
     })
   }
 
-  pub fn get_body(&self, request: GetBodyRequest) -> Result<TimeSeriesData> {
-    let response = self.make_request(request.to_url())?;
+  fn get_health_metric<T: DeserializeOwned>(
+    &self,
+    metric: HealthMetric,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<T>>, FitbitError> {
+    let request = GetHealthMetricRequest {
+      metric,
+      base_date,
+      end_date,
+    };
+    let response: HealthMetricResponse<T> = self.make_request(request.to_url(&self.base_url))?;
 
-    if let Some(body_weight) = response.body_weight {
-      Ok(TimeSeriesData { body_weight })
-    } else {
-      Err(anyhow!("Errors in response: {:?}", response))
+    match (response, metric.response_key()) {
+      (HealthMetricResponse::List(days), None) => Ok(days),
+      (HealthMetricResponse::Keyed(mut keyed), Some(key)) => keyed
+        .remove(key)
+        .ok_or_else(|| FitbitError::Parse(format!("No {} in response", key))),
+      _ => Err(FitbitError::Parse(format!(
+        "Unexpected response for {}",
+        metric.name()
+      ))),
     }
   }
 
-  pub fn get_weight_logs(&self, request: GetWeightLogsRequest) -> Result<Vec<WeightLog>> {
-    let response = self.make_request(request.to_url())?;
+  pub fn get_spo2(
+    &self,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<Spo2Value>>, FitbitError> {
+    self.get_health_metric(HealthMetric::Spo2, base_date, end_date)
+  }
+
+  pub fn get_hrv(
+    &self,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<HrvValue>>, FitbitError> {
+    self.get_health_metric(HealthMetric::Hrv, base_date, end_date)
+  }
+
+  pub fn get_breathing_rate(
+    &self,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<BreathingRateValue>>, FitbitError> {
+    self.get_health_metric(HealthMetric::BreathingRate, base_date, end_date)
+  }
+
+  pub fn get_skin_temperature(
+    &self,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<SkinTemperatureValue>>, FitbitError> {
+    self.get_health_metric(HealthMetric::SkinTemperature, base_date, end_date)
+  }
+
+  pub fn get_cardio_score(
+    &self,
+    base_date: NaiveDate,
+    end_date: NaiveDate,
+  ) -> Result<Vec<HealthMetricDay<CardioScoreValue>>, FitbitError> {
+    self.get_health_metric(HealthMetric::CardioScore, base_date, end_date)
+  }
+
+  /// The user's profile, fetched at most once a day. It's fetched without locale
+  /// headers, since it's where they come from.
+  pub fn profile(&self) -> Result<Profile, FitbitError> {
+    self.profile.get_or_fetch(|| {
+      let text = self.get_text_with_headers(GetProfileRequest.to_url(&self.base_url), &[])?;
+      let response: ProfileResponse =
+        serde_json::from_str(&text).map_err(|e| FitbitError::Parse(format!("{}: {}", e, text)))?;
+      Ok(response.user)
+    })
+  }
+
+  /// The devices paired with the account, fetched at most every few minutes.
+  pub fn devices(&self) -> Result<Vec<Device>, FitbitError> {
+    self
+      .devices
+      .get_or_fetch(|| self.make_request(GetDevicesRequest.to_url(&self.base_url)))
+  }
+
+  pub fn get_food_log(&self, request: GetFoodLogRequest) -> Result<FoodLogDay, FitbitError> {
+    self.make_request(request.to_url(&self.base_url))
+  }
+
+  pub fn get_water_logs(&self, request: GetWaterLogsRequest) -> Result<Vec<WaterLog>, FitbitError> {
+    let response: WaterLogsResponse = self.make_request(request.to_url(&self.base_url))?;
+    Ok(response.water)
+  }
+
+  /// Returns the requested sleep logs, following the pages of the list endpoint.
+  pub fn get_sleep_logs(&self, request: GetSleepLogsRequest) -> Result<Vec<SleepLog>, FitbitError> {
+    self.get_all_pages(request.to_url(&self.base_url), |response: SleepResponse| {
+      (response.sleep, response.pagination)
+    })
+  }
+
+  pub fn get_activity_logs(
+    &self,
+    request: GetActivityLogsRequest,
+  ) -> Result<Vec<ActivityLog>, FitbitError> {
+    self.get_all_pages(
+      request.to_url(&self.base_url),
+      |response: ActivityLogsResponse| (response.activities, response.pagination),
+    )
+  }
+
+  /// Returns the TCX file's XML.
+  pub fn get_activity_tcx(&self, request: GetActivityTcxRequest) -> Result<String, FitbitError> {
+    self.get_text(request.to_url(&self.base_url))
+  }
+
+  pub fn get_weight_logs(
+    &self,
+    request: GetWeightLogsRequest,
+  ) -> Result<Vec<WeightLog>, FitbitError> {
+    let response: GenericResponse = self.make_request(request.to_url(&self.base_url))?;
     if let Some(weight) = response.weight {
       Ok(weight)
     } else {
-      Err(anyhow!("Errors in response: {:?}", response))
+      Err(FitbitError::Parse(format!(
+        "No weight in response: {:?}",
+        response
+      )))
     }
   }
 }
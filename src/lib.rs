@@ -0,0 +1,29 @@
+//! Syncs data from Fitbit to destinations such as CSV files and Google Sheets.
+//!
+//! The web UI and command line live in the `fitsync` binary. This library holds
+//! everything else, so that it can be tested without them.
+
+use std::sync::{Arc, Mutex};
+
+use account::Accounts;
+use config::Config;
+use destination::Destinations;
+use sheets::SheetsClient;
+
+pub mod account;
+pub mod auth;
+pub mod config;
+pub mod destination;
+pub mod fitbit;
+pub mod runloop;
+pub mod schedule;
+pub mod sheets;
+pub mod sync;
+pub mod vault;
+
+pub struct AppState {
+  pub accounts: Accounts,
+  pub sheets_client: Option<Arc<SheetsClient>>,
+  pub config: Config,
+  pub destinations: Mutex<Destinations>,
+}
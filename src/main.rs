@@ -3,61 +3,134 @@
 #[macro_use]
 extern crate rocket;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use anyhow::Result;
-use config::Config;
-use destination::Destinations;
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
 use directories::ProjectDirs;
 use env_logger::Env;
-use fitbit::FitbitClient;
-use rocket::{fairing::AdHoc, Rocket};
+use fitsync::account::{Account, Accounts, DEFAULT_ACCOUNT};
+use fitsync::auth;
+use fitsync::config::Config;
+use fitsync::destination::Destinations;
+use fitsync::sheets::{self, SheetsClient};
+use fitsync::sync::SyncSession;
+use fitsync::vault::{self, Vault};
+use fitsync::{runloop, AppState};
+use rocket::config::Environment;
+use rocket::fairing::AdHoc;
 use rocket_contrib::serve::StaticFiles;
 
 mod api;
-mod auth;
-mod config;
-mod destination;
-mod fitbit;
-mod runloop;
-mod sync;
-
-pub struct AppState {
-  pub fitbit_client: FitbitClient,
-  pub config: Config,
-  pub destinations: Mutex<Destinations>,
-}
-
-fn launch_browser(r: &Rocket) {
-  let port = r.config().port;
 
-  webbrowser::open(&format!("http://localhost:{}/", port)).unwrap();
+fn launch_browser(public_url: &str) {
+  webbrowser::open(&format!("{}/", public_url)).unwrap();
 }
 
 fn main() -> Result<()> {
   env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-  let static_path = "static";
+  let project_dirs = ProjectDirs::from("org", "dubh", "fitsync").unwrap();
 
-  let _scheduler = runloop::start();
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+  match args.as_slice() {
+    [] => serve(&project_dirs),
+    ["auth", "fitbit"] => authorize_fitbit(&project_dirs, DEFAULT_ACCOUNT),
+    ["auth", "fitbit", account] => authorize_fitbit(&project_dirs, account),
+    ["auth", "google"] => authorize_google(&project_dirs),
+    ["vault", "rotate-key"] => vault::rotate_key(&project_dirs),
+    ["backfill", destination, start_date] => backfill(&project_dirs, destination, start_date, None),
+    ["backfill", destination, start_date, end_date] => {
+      backfill(&project_dirs, destination, start_date, Some(*end_date))
+    }
+    _ => Err(anyhow!(
+      "Usage: fitsync [auth fitbit [<account>] | auth google | vault rotate-key | \
+       backfill <destination> <start date> [<end date>]]"
+    )),
+  }
+}
 
-  let config = Config::load()?;
-  let fitbit_oauth = auth::OAuthClient::for_service("fitbit", &config.auth.fitbit)?;
-  let fitbit_client = FitbitClient::new(fitbit_oauth);
-  // let google_client  = auth::OAuthClient::for_service("google"", secrets)
+fn authorize_google(project_dirs: &ProjectDirs) -> Result<()> {
+  let vault = Vault::open(project_dirs)?;
+  let config = Config::load(&vault)?;
+  let mut google_oauth = sheets::google_oauth(&config, project_dirs, vault)?
+    .ok_or_else(|| anyhow!("Add a Google client id to config.json first"))?;
 
-  let project_dirs = ProjectDirs::from("org", "dubh", "fitsync").unwrap();
+  auth::authorize_from_terminal(&mut google_oauth)
+}
+
+fn authorize_fitbit(project_dirs: &ProjectDirs, account_id: &str) -> Result<()> {
+  let vault = Vault::open(project_dirs)?;
+  let config = Config::load(&vault)?;
+  anyhow::ensure!(
+    config.accounts.iter().any(|a| a == account_id),
+    "No account named {} in config.json",
+    account_id
+  );
+  let mut fitbit_oauth = Account::fitbit_oauth(account_id, &config, project_dirs, vault)?;
+
+  auth::authorize_from_terminal(&mut fitbit_oauth)
+}
 
-  let dest = Destinations::load(&project_dirs)?;
+/// Fetches a date range into a destination again. Dates are YYYY-MM-DD. Data that
+/// takes a request per day is only fetched for the end of a long range.
+fn backfill(
+  project_dirs: &ProjectDirs,
+  destination_id: &str,
+  start_date: &str,
+  end_date: Option<&str>,
+) -> Result<()> {
+  let start_date = parse_date(start_date)?;
+  let end_date = end_date.map(parse_date).transpose()?;
+
+  let vault = Vault::open(project_dirs)?;
+  let config = Config::load(&vault)?;
+  let accounts = Accounts::load(&config, project_dirs, &vault)?;
+  let sheets_client = sheets::google_oauth(&config, project_dirs, vault)?
+    .map(|oauth| Arc::new(SheetsClient::new(oauth, &config.sheets_base_url)));
+  let destinations = Mutex::new(Destinations::load(project_dirs, sheets_client)?);
+
+  SyncSession::start(&destinations, &accounts).backfill(destination_id, start_date, end_date)
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+  date
+    .parse()
+    .map_err(|e| anyhow!("Invalid date {:?}, expected YYYY-MM-DD: {}", date, e))
+}
+
+fn serve(project_dirs: &ProjectDirs) -> Result<()> {
+  let static_path = "static";
 
-  let app_state = AppState {
+  let vault = Vault::open(project_dirs)?;
+  let config = Config::load(&vault)?;
+  let public_url = config.server.public_url();
+  let accounts = Accounts::load(&config, project_dirs, &vault)?;
+  let sheets_client = sheets::google_oauth(&config, project_dirs, vault)?
+    .map(|oauth| Arc::new(SheetsClient::new(oauth, &config.sheets_base_url)));
+
+  let dest = Destinations::load(project_dirs, sheets_client.clone())?;
+
+  let rocket_config = rocket::Config::build(Environment::active()?)
+    .address(config.server.address.to_owned())
+    .port(config.server.port)
+    .finalize()?;
+
+  let app_state = Arc::new(AppState {
     config,
-    fitbit_client,
+    accounts,
+    sheets_client,
     destinations: Mutex::new(dest),
-  };
+  });
+
+  let _scheduler = runloop::start(app_state.clone());
 
-  rocket::ignite()
-    .attach(AdHoc::on_launch("Launch", launch_browser))
+  rocket::custom(rocket_config)
+    .attach(AdHoc::on_launch("Launch", move |_| {
+      launch_browser(&public_url)
+    }))
     .manage(app_state)
     .mount("/api/", api::get_api_routes())
     .mount("/auth/", api::get_auth_routes())
@@ -1,27 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
+use chrono::{DateTime, Local, TimeZone, Utc};
 use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
-use log::info;
+use log::{debug, error, info};
 
-pub fn synchronize() {
-  info!("Beginning sync...");
+use crate::destination::{Destination, DestinationId};
+use crate::sync::SyncSession;
+use crate::AppState;
 
-  info!("Sync completed");
+/// Tracks when each destination is next due to sync.
+struct RunLoop {
+  state: Arc<AppState>,
+  next_runs: HashMap<DestinationId, DateTime<Local>>,
 }
 
-fn synchronize_impl() -> Result<()> {
-  // TODO: Send http request to self.
-  Ok(())
+impl RunLoop {
+  fn is_due(&self, dest: &Destination, session: &SyncSession, now: DateTime<Local>) -> bool {
+    let schedule = dest
+      .schedule
+      .as_ref()
+      .unwrap_or(&self.state.config.schedule);
+    if schedule.is_quiet(now) {
+      return false;
+    }
+
+    // Back off from destinations that have been failing.
+    if let Some(retry_at) = session.destinations().retry_at(&dest.id) {
+      if Utc.from_utc_datetime(&retry_at) > now {
+        return false;
+      }
+    }
+
+    match self.next_runs.get(&dest.id) {
+      Some(next_run) => *next_run <= now,
+      None => true,
+    }
+  }
+
+  /// Syncs the destinations that are due, unless a sync is already in progress or
+  /// there's no authorized account to sync.
+  fn tick(&mut self) {
+    let state = self.state.clone();
+    if state.accounts.authorized().next().is_none() {
+      debug!("No authorized accounts, skipping sync");
+      return;
+    }
+
+    let mut session = match SyncSession::try_start(&state.destinations, &state.accounts) {
+      Some(session) => session,
+      None => {
+        debug!("A sync is already in progress, skipping");
+        return;
+      }
+    };
+
+    let now = Local::now();
+    let due: HashSet<DestinationId> = session
+      .destinations()
+      .config
+      .destinations
+      .iter()
+      .filter(|d| self.is_due(d, &session, now))
+      .map(|d| d.id.to_owned())
+      .collect();
+    if due.is_empty() {
+      return;
+    }
+
+    info!("Beginning sync of {:?}...", due);
+    match session.sync_matching(|d| due.contains(&d.id)) {
+      Ok(()) => info!("Sync completed"),
+      Err(e) => error!("Sync failed: {:?}", e),
+    }
+
+    for dest in session.destinations().config.destinations.iter() {
+      if due.contains(&dest.id) {
+        if let Err(e) = self.schedule_next_run(dest, now) {
+          error!("Unable to schedule {}: {:?}", dest.id, e);
+        }
+      }
+    }
+  }
+
+  /// Makes every destination due, as if its scheduled time had passed.
+  fn make_all_due(&mut self) {
+    let now = Local::now();
+    let destinations = self.state.destinations.lock().unwrap();
+    for dest in destinations.config.destinations.iter() {
+      self.next_runs.insert(dest.id.to_owned(), now);
+    }
+  }
+
+  fn schedule_next_run(&mut self, dest: &Destination, now: DateTime<Local>) -> Result<()> {
+    let schedule = dest
+      .schedule
+      .as_ref()
+      .unwrap_or(&self.state.config.schedule);
+    let next_run = schedule.next_run_after(now)?;
+    info!("Next sync of {} at {}", dest.id, next_run);
+    self.next_runs.insert(dest.id.to_owned(), next_run);
+    Ok(())
+  }
 }
 
-pub fn start() -> ScheduleHandle {
+pub fn start(state: Arc<AppState>) -> ScheduleHandle {
   info!("Starting run loop");
 
-  synchronize();
+  let mut run_loop = RunLoop {
+    state,
+    next_runs: HashMap::new(),
+  };
+  // Sync everything on the first tick. Quiet hours and backoff still apply.
+  run_loop.make_all_due();
 
   let mut scheduler = Scheduler::new();
 
-  scheduler.every(15.minutes()).run(synchronize);
+  // Each destination has its own schedule, so check every minute for those that
+  // are due.
+  scheduler.every(1.minute()).run(move || run_loop.tick());
   scheduler.watch_thread(Duration::from_millis(10000))
 }
@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Local, NaiveTime};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// A time of day range during which scheduled syncs don't run. The range may wrap
+/// past midnight (e.g. 23:00 to 07:00).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuietHours {
+  pub start: NaiveTime,
+  pub end: NaiveTime,
+}
+
+impl QuietHours {
+  fn contains(&self, time: NaiveTime) -> bool {
+    if self.start <= self.end {
+      time >= self.start && time < self.end
+    } else {
+      time >= self.start || time < self.end
+    }
+  }
+}
+
+/// When scheduled syncs run. Times are in the local timezone of the machine
+/// running fitsync.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+  /// Minutes between syncs. Ignored if `cron` is set.
+  #[serde(default = "Schedule::default_interval_minutes")]
+  pub interval_minutes: u32,
+  /// A cron expression with a leading seconds field, e.g. "0 30 2 * * *" for 2:30am
+  /// every day.
+  pub cron: Option<String>,
+  /// Up to this many seconds are randomly added to each scheduled time.
+  #[serde(default)]
+  pub jitter_seconds: u32,
+  pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for Schedule {
+  fn default() -> Self {
+    Schedule {
+      interval_minutes: Self::default_interval_minutes(),
+      cron: None,
+      jitter_seconds: 0,
+      quiet_hours: None,
+    }
+  }
+}
+
+impl Schedule {
+  fn default_interval_minutes() -> u32 {
+    15
+  }
+
+  fn cron_schedule(&self) -> Result<Option<cron::Schedule>> {
+    match self.cron {
+      Some(ref expression) => {
+        Ok(Some(cron::Schedule::from_str(expression).map_err(|e| {
+          anyhow!("Invalid cron expression {:?}: {}", expression, e)
+        })?))
+      }
+      None => Ok(None),
+    }
+  }
+
+  pub fn validate(&self) -> Result<()> {
+    anyhow::ensure!(
+      self.cron.is_some() || self.interval_minutes > 0,
+      "interval_minutes must be greater than zero"
+    );
+    self.cron_schedule()?;
+    Ok(())
+  }
+
+  /// The first scheduled time after `after`, without jitter.
+  pub fn next_after(&self, after: DateTime<Local>) -> Result<DateTime<Local>> {
+    match self.cron_schedule()? {
+      Some(schedule) => schedule
+        .after(&after)
+        .next()
+        .ok_or_else(|| anyhow!("Cron expression {:?} never fires", self.cron)),
+      None => Ok(after + Duration::minutes(self.interval_minutes as i64)),
+    }
+  }
+
+  /// The first scheduled time after `after`, with jitter applied.
+  pub fn next_run_after(&self, after: DateTime<Local>) -> Result<DateTime<Local>> {
+    let jitter = if self.jitter_seconds > 0 {
+      rand::thread_rng().gen_range(0..=self.jitter_seconds)
+    } else {
+      0
+    };
+    Ok(self.next_after(after)? + Duration::seconds(jitter as i64))
+  }
+
+  pub fn is_quiet(&self, time: DateTime<Local>) -> bool {
+    match self.quiet_hours {
+      Some(ref quiet_hours) => quiet_hours.contains(time.time()),
+      None => false,
+    }
+  }
+}
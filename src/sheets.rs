@@ -0,0 +1,230 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use directories::ProjectDirs;
+use reqwest::{
+  blocking::{Client, RequestBuilder},
+  header::AUTHORIZATION,
+  StatusCode, Url,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::account::DEFAULT_ACCOUNT;
+use crate::auth::{OAuthClient, ServiceUrls};
+use crate::config::Config;
+use crate::vault::SharedVault;
+
+pub static DEFAULT_SHEETS_BASE_URL: &str = "https://sheets.googleapis.com/v4";
+pub static DEFAULT_GOOGLE_OAUTH_BASE_URL: &str = "https://oauth2.googleapis.com";
+
+#[derive(Deserialize)]
+struct ValueRange {
+  values: Option<Vec<Vec<Value>>>,
+}
+
+/// The Google OAuth client, if a Google client ID is configured. Google is authorized
+/// once for all accounts.
+pub fn google_oauth(
+  config: &Config,
+  project_dirs: &ProjectDirs,
+  vault: SharedVault,
+) -> Result<Option<OAuthClient>> {
+  if config.auth.google.id.is_empty() {
+    return Ok(None);
+  }
+
+  Ok(Some(OAuthClient::for_service(
+    "google",
+    ServiceUrls::google(&config.google_oauth_base_url),
+    DEFAULT_ACCOUNT,
+    &config.auth.google,
+    &config.server.public_url(),
+    project_dirs,
+    vault,
+  )?))
+}
+
+/// A minimal client for the parts of the Google Sheets API that destinations use.
+pub struct SheetsClient {
+  pub oauth: Mutex<OAuthClient>,
+  http_client: Client,
+  base_url: String,
+}
+
+impl fmt::Debug for SheetsClient {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SheetsClient")
+      .field("base_url", &self.base_url)
+      .finish()
+  }
+}
+
+impl SheetsClient {
+  pub fn new(oauth: OAuthClient, base_url: &str) -> Self {
+    SheetsClient {
+      oauth: Mutex::new(oauth),
+      http_client: Client::new(),
+      base_url: base_url.trim_end_matches('/').to_owned(),
+    }
+  }
+
+  fn spreadsheet_url(&self, spreadsheet_id: &str, path: &[&str]) -> Result<Url> {
+    let mut url = Url::parse(&self.base_url)?;
+    url
+      .path_segments_mut()
+      .map_err(|_| anyhow!("Invalid Sheets base URL: {}", self.base_url))?
+      .push("spreadsheets")
+      .push(spreadsheet_id)
+      .extend(path);
+    Ok(url)
+  }
+
+  fn values_url(&self, spreadsheet_id: &str, range: &str) -> Result<Url> {
+    self.spreadsheet_url(spreadsheet_id, &["values", range])
+  }
+
+  /// Sends a request, refreshing the access token and retrying once if it was
+  /// rejected.
+  fn send<F>(&self, build: F) -> Result<Value>
+  where
+    F: Fn(&Client) -> RequestBuilder,
+  {
+    let mut oauth = self.oauth.lock().unwrap();
+    let mut res = build(&self.http_client)
+      .header(AUTHORIZATION, format!("Bearer {}", oauth.get_secret()?))
+      .send()?;
+
+    if res.status() == StatusCode::UNAUTHORIZED {
+      oauth.refresh_tokens()?;
+      res = build(&self.http_client)
+        .header(AUTHORIZATION, format!("Bearer {}", oauth.get_secret()?))
+        .send()?;
+    }
+
+    let status = res.status();
+    let text = res.text()?;
+    anyhow::ensure!(
+      status.is_success(),
+      "Sheets request failed with {}: {}",
+      status,
+      text
+    );
+    Ok(serde_json::from_str(&text)?)
+  }
+
+  /// Returns the values in the first column of `sheet`, one per row. Empty cells
+  /// are returned as empty strings, so that the index of a value is its row.
+  pub fn get_first_column(&self, spreadsheet_id: &str, sheet: &str) -> Result<Vec<String>> {
+    let url = self.values_url(spreadsheet_id, &format!("'{}'!A:A", sheet))?;
+    let response: ValueRange = serde_json::from_value(self.send(|c| c.get(url.clone()))?)?;
+
+    Ok(
+      response
+        .values
+        .unwrap_or_default()
+        .into_iter()
+        .map(|row| row.first().map(cell_text).unwrap_or_default())
+        .collect(),
+    )
+  }
+
+  /// Appends rows after the last row of data in `sheet`.
+  pub fn append_rows(
+    &self,
+    spreadsheet_id: &str,
+    sheet: &str,
+    rows: Vec<Vec<Value>>,
+  ) -> Result<()> {
+    let mut url = self.values_url(spreadsheet_id, &format!("'{}':append", sheet))?;
+    url
+      .query_pairs_mut()
+      .append_pair("valueInputOption", "RAW")
+      .append_pair("insertDataOption", "INSERT_ROWS");
+    let body = json!({ "values": rows });
+
+    self.send(|c| c.post(url.clone()).json(&body))?;
+    Ok(())
+  }
+
+  /// Appends the rows whose first column isn't already present in `sheet`.
+  pub fn append_new_rows(
+    &self,
+    spreadsheet_id: &str,
+    sheet: &str,
+    rows: Vec<Vec<Value>>,
+  ) -> Result<()> {
+    let existing: HashSet<String> = self
+      .get_first_column(spreadsheet_id, sheet)?
+      .into_iter()
+      .collect();
+
+    let new_rows: Vec<Vec<Value>> = rows
+      .into_iter()
+      .filter(|row| match row.first() {
+        Some(key) => !existing.contains(&cell_text(key)),
+        None => false,
+      })
+      .collect();
+
+    if new_rows.is_empty() {
+      return Ok(());
+    }
+    self.append_rows(spreadsheet_id, sheet, new_rows)
+  }
+
+  /// Replaces the rows whose first column is already present in `sheet`, and appends
+  /// the others.
+  pub fn update_or_append_rows(
+    &self,
+    spreadsheet_id: &str,
+    sheet: &str,
+    rows: Vec<Vec<Value>>,
+  ) -> Result<()> {
+    let existing: HashMap<String, usize> = self
+      .get_first_column(spreadsheet_id, sheet)?
+      .into_iter()
+      .enumerate()
+      .map(|(index, key)| (key, index))
+      .collect();
+
+    let mut updates = Vec::new();
+    let mut new_rows = Vec::new();
+    for row in rows {
+      let index = match row.first() {
+        Some(key) => existing.get(&cell_text(key)),
+        None => continue,
+      };
+      match index {
+        // Rows are numbered from 1.
+        Some(index) => updates.push(json!({
+          "range": format!("'{}'!A{}", sheet, index + 1),
+          "values": [row],
+        })),
+        None => new_rows.push(row),
+      }
+    }
+
+    if !updates.is_empty() {
+      let url = self.spreadsheet_url(spreadsheet_id, &["values:batchUpdate"])?;
+      let body = json!({ "valueInputOption": "RAW", "data": updates });
+      self.send(|c| c.post(url.clone()).json(&body))?;
+    }
+    if !new_rows.is_empty() {
+      self.append_rows(spreadsheet_id, sheet, new_rows)?;
+    }
+    Ok(())
+  }
+}
+
+/// The text of a cell, as the Sheets API returns it when reading values.
+fn cell_text(value: &Value) -> String {
+  match value {
+    Value::String(s) => s.to_owned(),
+    other => other.to_string(),
+  }
+}
@@ -1,43 +1,229 @@
 use std::sync::{Mutex, MutexGuard};
 
 use crate::{
-  destination::{Destination, Destinations},
-  fitbit::{BodyType, DateOrToday, FitbitClient, GetBodyRequest},
+  account::Accounts,
+  destination::{f32_cell, log_id_cell, Destination, Destinations, Table, WeightMode},
+  fitbit::{
+    ActivityResource, BodyType, DateOrToday, DetailLevel, FitbitClient, FitbitError,
+    GetActivityLogsRequest, GetActivityRequest, GetActivityTcxRequest, GetBodyRequest,
+    GetFoodLogRequest, GetHeartRateIntradayRequest, GetHeartRateRequest, GetSleepLogsRequest,
+    GetWaterLogsRequest, GetWeightLogsRequest, HealthMetric, Profile, TimeSeriesValue,
+    MAX_WEIGHT_LOG_DAYS,
+  },
 };
-use anyhow::Result;
-use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
 
-use log::info;
+use log::{info, warn};
+use serde_json::{json, Value};
 
 pub struct SyncSession<'a> {
   destinations: MutexGuard<'a, Destinations>,
-  fitbit_client: &'a FitbitClient,
+  accounts: &'a Accounts,
 }
 
 impl<'a> SyncSession<'a> {
-  pub fn start(destinations: &'a Mutex<Destinations>, fitbit_client: &'a FitbitClient) -> Self {
+  pub fn start(destinations: &'a Mutex<Destinations>, accounts: &'a Accounts) -> Self {
     let locked = destinations.lock().unwrap();
 
     SyncSession {
       destinations: locked,
-      fitbit_client,
+      accounts,
     }
   }
 
+  /// Starts a session, unless another session is already in progress.
+  pub fn try_start(destinations: &'a Mutex<Destinations>, accounts: &'a Accounts) -> Option<Self> {
+    let locked = destinations.try_lock().ok()?;
+
+    Some(SyncSession {
+      destinations: locked,
+      accounts,
+    })
+  }
+
+  pub fn destinations(&self) -> &Destinations {
+    &self.destinations
+  }
+
   pub fn sync_all(&mut self) -> Result<()> {
-    self.destinations.process(sync, self.fitbit_client)?;
-    Ok(())
+    self.sync_matching(|_| true)
+  }
+
+  /// Fetches the data from `start_date` to `end_date` (or today) into a destination
+  /// again, e.g. to fill a gap or to add data from before its start date. Rows
+  /// already in the destination are replaced or skipped as in a normal sync, and the
+  /// time of the last sync isn't changed.
+  pub fn backfill(
+    &mut self,
+    id: &str,
+    start_date: NaiveDate,
+    end_date: Option<NaiveDate>,
+  ) -> Result<()> {
+    let destination = self
+      .destinations
+      .config
+      .destinations
+      .iter()
+      .find(|d| d.id == id)
+      .ok_or_else(|| anyhow!("No destination named {}", id))?;
+    let fitbit_client = &self.accounts.get(&destination.account)?.fitbit_client;
+    let end_date = match end_date {
+      Some(date) => date,
+      None => with_retries(|| fitbit_client.profile())?.today(),
+    };
+    anyhow::ensure!(
+      start_date <= end_date,
+      "The start date {} is after the end date {}",
+      start_date,
+      end_date
+    );
+
+    info!(
+      "Backfilling destination {} from {} to {}",
+      id, start_date, end_date
+    );
+    sync_dates(
+      destination,
+      fitbit_client,
+      &SyncDates::backfill(start_date, end_date),
+    )
+  }
+
+  /// Syncs the destinations that match `filter`. A destination that fails doesn't
+  /// stop the others from being synced.
+  pub fn sync_matching<F>(&mut self, filter: F) -> Result<()>
+  where
+    F: Fn(&Destination) -> bool,
+  {
+    for dest in self.destinations.config.destinations.iter() {
+      if self.accounts.get(&dest.account).is_err() {
+        warn!("Destination {:?} is for an unknown account", dest);
+      }
+    }
+
+    let mut failures = 0;
+    for account in self.accounts.authorized() {
+      info!("Syncing account {}", account.id);
+      failures += self.destinations.process(
+        |d| d.account == account.id && filter(d),
+        sync,
+        &account.fitbit_client,
+      )?;
+    }
+
+    if failures > 0 {
+      Err(anyhow!("{} destination(s) failed to sync", failures))
+    } else {
+      Ok(())
+    }
   }
 }
 
-fn end_date_for(start_date: NaiveDate) -> NaiveDate {
-  let mut end_date = start_date + Duration::days(365);
-  let today = Utc::now().naive_utc().date();
-  if end_date > today {
-    end_date = today;
+/// How many times a request that failed with a transient error is retried.
+const MAX_RETRIES: u32 = 2;
+const RETRY_DELAY_SECS: u64 = 10;
+
+/// Runs a Fitbit request, retrying it if it fails with a transient error. Tokens are
+/// only forgotten by the client when the refresh token is rejected, so a failure
+/// here never logs the account out.
+fn with_retries<T, F>(request: F) -> Result<T>
+where
+  F: Fn() -> Result<T, FitbitError>,
+{
+  let mut attempts = 0;
+  loop {
+    match request() {
+      Ok(value) => return Ok(value),
+      Err(e) if e.is_retryable() && attempts < MAX_RETRIES => {
+        attempts += 1;
+        warn!("Fitbit request failed, retrying: {}", e);
+        std::thread::sleep(std::time::Duration::from_secs(RETRY_DELAY_SECS));
+      }
+      Err(e) => return Err(e.into()),
+    }
+  }
+}
+
+/// The longest date range that daily time series are requested for at once.
+const MAX_TIME_SERIES_DAYS: i64 = 365;
+
+/// The dates a sync covers. Fitbit's dates are in the user's timezone, so these are
+/// too.
+struct SyncDates {
+  start_date: NaiveDate,
+  end_date: NaiveDate,
+}
+
+impl SyncDates {
+  /// From the day of the last sync, or the start date if there hasn't been one, up to
+  /// today.
+  fn new(profile: &Profile, start_date: NaiveDate, last_synced: Option<NaiveDateTime>) -> Self {
+    SyncDates {
+      start_date: last_synced
+        .map(|dt| profile.to_local(dt).date())
+        .unwrap_or(start_date),
+      end_date: profile.today(),
+    }
+  }
+
+  fn backfill(start_date: NaiveDate, end_date: NaiveDate) -> Self {
+    SyncDates {
+      start_date,
+      end_date,
+    }
+  }
+
+  /// Splits the dates into ranges of at most `max_days` days after their start date.
+  /// Each range starts on the date the previous one ended.
+  fn ranges(&self, max_days: i64) -> Vec<(NaiveDate, NaiveDate)> {
+    let mut start_date = self.start_date;
+
+    let mut ranges = Vec::new();
+    loop {
+      let end_date = (start_date + Duration::days(max_days)).min(self.end_date);
+      ranges.push((start_date, end_date));
+      if end_date >= self.end_date {
+        break;
+      }
+      start_date = end_date;
+    }
+
+    ranges
+  }
+
+  /// Each of the dates, up to the last `history_days` of them. This is for data that
+  /// takes a request per day, so that a first sync or a backfill of years doesn't
+  /// make thousands of requests.
+  fn days(&self, history_days: i64) -> Vec<NaiveDate> {
+    let mut date = self
+      .start_date
+      .max(self.end_date - Duration::days(history_days));
+    if date > self.start_date {
+      warn!(
+        "Only syncing data that takes a request per day from {}. Backfill \
+         {} to {} in ranges of {} days or fewer to fetch the rest.",
+        date,
+        self.start_date,
+        date.pred(),
+        history_days + 1
+      );
+    }
+
+    let mut days = Vec::new();
+    while date <= self.end_date {
+      days.push(date);
+      date = date.succ();
+    }
+    days
   }
 
-  end_date
+  /// The date to list logs after, which list endpoints exclude. Logs may be uploaded
+  /// some time after they end, so on later syncs the day of the last sync is fetched
+  /// again.
+  fn list_after_date(&self) -> NaiveDate {
+    self.start_date.pred()
+  }
 }
 
 fn sync(
@@ -47,27 +233,527 @@ fn sync(
 ) -> Result<()> {
   info!("Syncing to destination {:?}", destination);
 
-  let last_synced_date = last_synced.map(|dt| dt.date());
-  let mut start_date = last_synced_date.unwrap_or_else(|| NaiveDate::from_ymd(2016, 1, 1));
-  let mut end_date = end_date_for(start_date);
+  let profile = with_retries(|| fitbit_client.profile())?;
+  let start_date = destination.start_date.unwrap_or(profile.member_since);
+  sync_dates(
+    destination,
+    fitbit_client,
+    &SyncDates::new(&profile, start_date, last_synced),
+  )
+}
 
-  let now = Utc::now().naive_utc().date();
+fn sync_dates(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  for body_type in destination.body_metrics().iter() {
+    if *body_type == BodyType::Weight && destination.weight_mode == WeightMode::Logs {
+      sync_weight_logs(destination, fitbit_client, dates)?;
+    } else {
+      sync_time_series(destination, dates, &body_type.name(), |start, end| {
+        fitbit_client.get_body(GetBodyRequest::for_date_range(
+          *body_type,
+          DateOrToday::OnDate(start),
+          end,
+        ))
+      })?;
+    }
+  }
 
-  loop {
-    let result = fitbit_client.get_body(GetBodyRequest::for_date_range(
-      BodyType::Weight,
-      DateOrToday::OnDate(start_date),
-      end_date,
-    ))?;
-    destination.append_data(result.body_weight)?;
+  for resource in destination.activity_metrics.iter() {
+    sync_activity(destination, fitbit_client, dates, *resource)?;
+  }
 
-    if end_date == now {
-      break;
+  for metric in destination.health_metrics.iter() {
+    sync_health_metric(destination, fitbit_client, dates, *metric)?;
+  }
+
+  if destination.heart_rate {
+    sync_heart_rate(destination, fitbit_client, dates)?;
+  }
+  if destination.heart_rate_intraday {
+    sync_heart_rate_intraday(destination, fitbit_client, dates)?;
+  }
+  if destination.sleep || destination.sleep_stages {
+    sync_sleep(destination, fitbit_client, dates)?;
+  }
+  if destination.nutrition || destination.food_logs {
+    sync_food_logs(destination, fitbit_client, dates)?;
+  }
+  if destination.water_logs {
+    sync_water_logs(destination, fitbit_client, dates)?;
+  }
+  if destination.activity_logs {
+    sync_activity_logs(destination, fitbit_client, dates)?;
+  }
+
+  Ok(())
+}
+
+fn sync_time_series<F>(
+  destination: &Destination,
+  dates: &SyncDates,
+  series: &str,
+  fetch: F,
+) -> Result<()>
+where
+  F: Fn(NaiveDate, NaiveDate) -> Result<Vec<TimeSeriesValue>, FitbitError>,
+{
+  for (start_date, end_date) in dates.ranges(MAX_TIME_SERIES_DAYS) {
+    let values = with_retries(|| fetch(start_date, end_date))?;
+    destination.append_data(series, values)?;
+  }
+
+  Ok(())
+}
+
+/// Activity totals are written as a table keyed by date rather than as a time
+/// series, so that days with the same total are all kept, and today's partial total
+/// is replaced by the next sync.
+fn sync_activity(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+  resource: ActivityResource,
+) -> Result<()> {
+  for (start_date, end_date) in dates.ranges(MAX_TIME_SERIES_DAYS) {
+    let values = with_retries(|| {
+      fitbit_client.get_activity(GetActivityRequest::for_date_range(
+        resource,
+        DateOrToday::OnDate(start_date),
+        end_date,
+      ))
+    })?;
+
+    let mut table = Table::new(&resource.name(), &["dateTime", "value"]);
+    table.rows = values
+      .into_iter()
+      .map(|v| vec![v.date_time.to_string().into(), f32_cell(v.value)])
+      .collect();
+    destination.append_table(table)?;
+  }
+
+  Ok(())
+}
+
+fn sync_weight_logs(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  // The range includes both ends.
+  for (start_date, end_date) in dates.ranges(MAX_WEIGHT_LOG_DAYS - 1) {
+    let logs = with_retries(|| {
+      fitbit_client.get_weight_logs(GetWeightLogsRequest::for_date_range(start_date, end_date))
+    })?;
+    destination.append_weight_logs(logs)?;
+  }
+
+  Ok(())
+}
+
+fn sync_health_metric(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+  metric: HealthMetric,
+) -> Result<()> {
+  // The range includes both ends.
+  for (start_date, end_date) in dates.ranges(metric.max_days() - 1) {
+    let table = with_retries(|| health_metric_table(fitbit_client, metric, start_date, end_date))?;
+    destination.append_table(table)?;
+  }
+
+  Ok(())
+}
+
+fn health_metric_table(
+  fitbit_client: &FitbitClient,
+  metric: HealthMetric,
+  start_date: NaiveDate,
+  end_date: NaiveDate,
+) -> Result<Table, FitbitError> {
+  let (columns, rows): (&[&'static str], Vec<Vec<Value>>) = match metric {
+    HealthMetric::Spo2 => (
+      &["date", "avg", "min", "max"],
+      fitbit_client
+        .get_spo2(start_date, end_date)?
+        .into_iter()
+        .map(|day| {
+          vec![
+            day.date_time.to_string().into(),
+            day.value.avg.into(),
+            day.value.min.into(),
+            day.value.max.into(),
+          ]
+        })
+        .collect(),
+    ),
+    HealthMetric::Hrv => (
+      &["date", "daily_rmssd", "deep_rmssd"],
+      fitbit_client
+        .get_hrv(start_date, end_date)?
+        .into_iter()
+        .map(|day| {
+          vec![
+            day.date_time.to_string().into(),
+            day.value.daily_rmssd.into(),
+            day.value.deep_rmssd.into(),
+          ]
+        })
+        .collect(),
+    ),
+    HealthMetric::BreathingRate => (
+      &["date", "breathing_rate"],
+      fitbit_client
+        .get_breathing_rate(start_date, end_date)?
+        .into_iter()
+        .map(|day| {
+          vec![
+            day.date_time.to_string().into(),
+            day.value.breathing_rate.into(),
+          ]
+        })
+        .collect(),
+    ),
+    HealthMetric::SkinTemperature => (
+      &["date", "nightly_relative"],
+      fitbit_client
+        .get_skin_temperature(start_date, end_date)?
+        .into_iter()
+        .map(|day| {
+          vec![
+            day.date_time.to_string().into(),
+            day.value.nightly_relative.into(),
+          ]
+        })
+        .collect(),
+    ),
+    HealthMetric::CardioScore => (
+      &["date", "vo2_max"],
+      fitbit_client
+        .get_cardio_score(start_date, end_date)?
+        .into_iter()
+        .map(|day| vec![day.date_time.to_string().into(), day.value.vo2_max.into()])
+        .collect(),
+    ),
+  };
+
+  let mut table = Table::new(&metric.name(), columns);
+  table.rows = rows;
+  Ok(table)
+}
+
+/// The heart rate zones in the order they're written, with the names Fitbit uses.
+const HEART_RATE_ZONES: [&str; 4] = ["Out of Range", "Fat Burn", "Cardio", "Peak"];
+
+fn sync_heart_rate(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  for (start_date, end_date) in dates.ranges(MAX_TIME_SERIES_DAYS) {
+    let days = with_retries(|| {
+      fitbit_client.get_heart_rate(GetHeartRateRequest::for_date_range(
+        DateOrToday::OnDate(start_date),
+        end_date,
+      ))
+    })?;
+
+    let mut table = Table::new(
+      "heart_rate",
+      &[
+        "date",
+        "resting_heart_rate",
+        "out_of_range_minutes",
+        "fat_burn_minutes",
+        "cardio_minutes",
+        "peak_minutes",
+      ],
+    );
+    for day in days {
+      let mut row = vec![
+        day.date_time.to_string().into(),
+        json!(day.value.resting_heart_rate),
+      ];
+      row.extend(
+        HEART_RATE_ZONES
+          .iter()
+          .map(|zone| json!(day.zone_minutes(zone))),
+      );
+      table.rows.push(row);
     }
+    destination.append_table(table)?;
+  }
+
+  Ok(())
+}
+
+/// How many days of intraday data are synced at most, before the end of the sync.
+/// Each day takes a request, so syncing years of it would use up the rate limit for
+/// days.
+const INTRADAY_HISTORY_DAYS: i64 = 30;
+/// Likewise for food and water logs, which are also requested a day at a time.
+const DAILY_LOG_HISTORY_DAYS: i64 = 90;
+
+fn sync_heart_rate_intraday(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  // The days are written together, so that the destination is only read and
+  // rewritten once.
+  let mut table = Table::new("heart_rate_intraday", &["date_time", "heart_rate"]);
+  for date in dates.days(INTRADAY_HISTORY_DAYS) {
+    let values = with_retries(|| {
+      fitbit_client.get_heart_rate_intraday(GetHeartRateIntradayRequest {
+        date: DateOrToday::OnDate(date),
+        detail_level: DetailLevel::OneMinute,
+      })
+    })?;
 
-    start_date = end_date;
-    end_date = end_date_for(start_date);
+    for value in values {
+      table.rows.push(vec![
+        date.and_time(value.time).to_string().into(),
+        value.value.into(),
+      ]);
+    }
+  }
+
+  destination.append_table(table)
+}
+
+/// The sleep stages in the order they're written. Classic logs have other levels,
+/// so their stage columns are empty.
+const SLEEP_STAGES: [&str; 4] = ["deep", "light", "rem", "wake"];
+
+fn sync_sleep(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  // Rows for sessions that were already written are replaced. The list goes up to
+  // today, which is past the end of a backfill.
+  let after_date = dates.list_after_date();
+  let logs: Vec<_> =
+    with_retries(|| fitbit_client.get_sleep_logs(GetSleepLogsRequest::after_date(after_date)))?
+      .into_iter()
+      .filter(|log| log.date_of_sleep <= dates.end_date)
+      .collect();
+
+  let mut sessions = Table::new(
+    "sleep",
+    &[
+      "log_id",
+      "date_of_sleep",
+      "start_time",
+      "end_time",
+      "is_main_sleep",
+      "type",
+      "efficiency",
+      "minutes_asleep",
+      "minutes_awake",
+      "minutes_to_fall_asleep",
+      "time_in_bed",
+      "deep_minutes",
+      "light_minutes",
+      "rem_minutes",
+      "wake_minutes",
+    ],
+  );
+  let mut stages = Table::new("sleep_stages", &["date_time", "log_id", "level", "seconds"]);
+
+  for log in logs.iter() {
+    let mut row = vec![
+      log_id_cell(log.log_id),
+      log.date_of_sleep.to_string().into(),
+      log.start_time.to_string().into(),
+      log.end_time.to_string().into(),
+      log.is_main_sleep.into(),
+      log.log_type.to_owned().into(),
+      log.efficiency.into(),
+      log.minutes_asleep.into(),
+      log.minutes_awake.into(),
+      log.minutes_to_fall_asleep.into(),
+      log.time_in_bed.into(),
+    ];
+    row.extend(
+      SLEEP_STAGES
+        .iter()
+        .map(|stage| json!(log.level_minutes(stage))),
+    );
+    sessions.rows.push(row);
+
+    for stage in log.levels.data.iter() {
+      stages.rows.push(vec![
+        stage.date_time.to_string().into(),
+        log_id_cell(log.log_id),
+        stage.level.to_owned().into(),
+        stage.seconds.into(),
+      ]);
+    }
+  }
+
+  if destination.sleep {
+    destination.append_table(sessions)?;
+  }
+  if destination.sleep_stages {
+    destination.append_table(stages)?;
+  }
+
+  Ok(())
+}
+
+fn sync_food_logs(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  // The days are written together, so that each table is only read and rewritten
+  // once.
+  let mut nutrition = Table::new(
+    "nutrition",
+    &[
+      "date", "calories", "carbs", "fat", "fiber", "protein", "sodium", "water",
+    ],
+  );
+  let mut food_logs = Table::new(
+    "food_logs",
+    &[
+      "log_id", "date", "meal", "name", "brand", "amount", "unit", "calories", "carbs", "fat",
+      "fiber", "protein", "sodium",
+    ],
+  );
+
+  for date in dates.days(DAILY_LOG_HISTORY_DAYS) {
+    let day = with_retries(|| {
+      fitbit_client.get_food_log(GetFoodLogRequest {
+        date: DateOrToday::OnDate(date),
+      })
+    })?;
+
+    let totals = &day.summary.nutrients;
+    nutrition.rows.push(vec![
+      date.to_string().into(),
+      totals.calories.into(),
+      totals.carbs.into(),
+      totals.fat.into(),
+      totals.fiber.into(),
+      totals.protein.into(),
+      totals.sodium.into(),
+      day.summary.water.into(),
+    ]);
+
+    for entry in day.foods.iter() {
+      let food = &entry.logged_food;
+      let values = &entry.nutritional_values;
+      food_logs.rows.push(vec![
+        log_id_cell(entry.log_id),
+        entry.log_date.to_string().into(),
+        food.meal().into(),
+        food.name.to_owned().into(),
+        food.brand.to_owned().into(),
+        food.amount.into(),
+        json!(food.unit_name()),
+        values.calories.into(),
+        values.carbs.into(),
+        values.fat.into(),
+        values.fiber.into(),
+        values.protein.into(),
+        values.sodium.into(),
+      ]);
+    }
+  }
+
+  if destination.nutrition {
+    destination.append_table(nutrition)?;
+  }
+  if destination.food_logs {
+    destination.append_table(food_logs)?;
   }
 
   Ok(())
 }
+
+fn sync_water_logs(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  let mut table = Table::new("water_logs", &["log_id", "date", "amount"]);
+  for date in dates.days(DAILY_LOG_HISTORY_DAYS) {
+    let logs = with_retries(|| {
+      fitbit_client.get_water_logs(GetWaterLogsRequest {
+        date: DateOrToday::OnDate(date),
+      })
+    })?;
+
+    for log in logs {
+      table.rows.push(vec![
+        log_id_cell(log.log_id),
+        date.to_string().into(),
+        log.amount.into(),
+      ]);
+    }
+  }
+
+  destination.append_table(table)
+}
+
+fn sync_activity_logs(
+  destination: &Destination,
+  fitbit_client: &FitbitClient,
+  dates: &SyncDates,
+) -> Result<()> {
+  let after_date = dates.list_after_date();
+  let logs: Vec<_> = with_retries(|| {
+    fitbit_client.get_activity_logs(GetActivityLogsRequest::after_date(after_date))
+  })?
+  .into_iter()
+  .filter(|log| log.start_time.naive_local().date() <= dates.end_date)
+  .collect();
+
+  if destination.takes_tcx() {
+    for log in logs.iter().filter(|log| log.tcx_link.is_some()) {
+      let tcx = with_retries(|| {
+        fitbit_client.get_activity_tcx(GetActivityTcxRequest { log_id: log.log_id })
+      })?;
+      destination.append_activity_tcx(log, tcx)?;
+    }
+    return Ok(());
+  }
+
+  let mut table = Table::new(
+    "activities",
+    &[
+      "log_id",
+      "start_time",
+      "activity",
+      "log_type",
+      "duration_seconds",
+      "active_duration_seconds",
+      "average_heart_rate",
+      "calories",
+      "distance",
+      "distance_unit",
+      "steps",
+    ],
+  );
+  for log in logs {
+    table.rows.push(vec![
+      log_id_cell(log.log_id),
+      log.start_time.naive_local().to_string().into(),
+      log.activity_name.into(),
+      log.log_type.into(),
+      (log.duration / 1000).into(),
+      (log.active_duration / 1000).into(),
+      json!(log.average_heart_rate),
+      log.calories.into(),
+      json!(log.distance),
+      json!(log.distance_unit),
+      json!(log.steps),
+    ]);
+  }
+  destination.append_table(table)
+}
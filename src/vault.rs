@@ -0,0 +1,302 @@
+use std::{
+  collections::HashMap,
+  fs::{read, read_to_string, File, OpenOptions},
+  io::Write,
+  os::unix::{
+    fs::{OpenOptionsExt, PermissionsExt},
+    io::AsRawFd,
+  },
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use directories::ProjectDirs;
+use log::info;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+static PASSPHRASE_VAR: &str = "FITSYNC_VAULT_PASSPHRASE";
+static KEY_FILE_VAR: &str = "FITSYNC_VAULT_KEY_FILE";
+static NEW_PASSPHRASE_VAR: &str = "FITSYNC_NEW_VAULT_PASSPHRASE";
+static NEW_KEY_FILE_VAR: &str = "FITSYNC_NEW_VAULT_KEY_FILE";
+
+const VAULT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+pub type SharedVault = Arc<Mutex<Vault>>;
+
+/// The secret that the vault encryption key is derived from.
+pub enum KeySource {
+  Passphrase(String),
+  KeyFile(PathBuf),
+}
+
+impl KeySource {
+  fn from_env(passphrase_var: &str, key_file_var: &str) -> Result<Self> {
+    if let Ok(passphrase) = std::env::var(passphrase_var) {
+      Ok(KeySource::Passphrase(passphrase))
+    } else if let Ok(key_file) = std::env::var(key_file_var) {
+      Ok(KeySource::KeyFile(PathBuf::from(key_file)))
+    } else {
+      Err(anyhow!(
+        "Set {} or {} to unlock the credential vault",
+        passphrase_var,
+        key_file_var
+      ))
+    }
+  }
+
+  fn secret(&self) -> Result<Vec<u8>> {
+    match self {
+      KeySource::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+      KeySource::KeyFile(path) => Ok(read(path)?),
+    }
+  }
+
+  fn derive_key(&self, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+      .hash_password_into(&self.secret()?, salt, &mut key)
+      .map_err(|e| anyhow!("Unable to derive vault key: {}", e))?;
+    Ok(key)
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+  version: u32,
+  salt: String,
+  nonce: String,
+  ciphertext: String,
+}
+
+/// An encrypted store of named credentials (client secrets and OAuth tokens).
+///
+/// The whole vault is re-encrypted with a fresh nonce every time it's written.
+/// Other processes, such as the command line while the server runs, may share the
+/// file, so changes are made under a lock to the latest contents on disk.
+pub struct Vault {
+  path: PathBuf,
+  salt: Vec<u8>,
+  key: [u8; 32],
+  entries: HashMap<String, String>,
+  /// Held while this vault has the file locked.
+  lock_file: Option<File>,
+}
+
+impl Vault {
+  pub fn open(project_dirs: &ProjectDirs) -> Result<SharedVault> {
+    let key_source = KeySource::from_env(PASSPHRASE_VAR, KEY_FILE_VAR)?;
+    let path = project_dirs.data_dir().join("credentials.vault");
+
+    Ok(Arc::new(Mutex::new(Vault::open_with_key(
+      path,
+      &key_source,
+    )?)))
+  }
+
+  /// Opens the vault at `path`. It's created on the first write if it doesn't exist.
+  pub fn open_with_key(path: PathBuf, key_source: &KeySource) -> Result<Self> {
+    let file = match read_file(&path)? {
+      Some(file) => file,
+      None => {
+        info!("Creating credential vault at {:?}", path);
+        let salt = random_bytes(SALT_LEN);
+        let key = key_source.derive_key(&salt)?;
+        return Ok(Vault {
+          path,
+          salt,
+          key,
+          entries: HashMap::new(),
+          lock_file: None,
+        });
+      }
+    };
+
+    let salt = base64::decode(&file.salt)?;
+    let key = key_source.derive_key(&salt)?;
+    let entries = decrypt(&file, &key)?;
+
+    Ok(Vault {
+      path,
+      salt,
+      key,
+      entries,
+      lock_file: None,
+    })
+  }
+
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self.entries.get(name).map(|v| v.as_str())
+  }
+
+  /// Sets `name`, keeping any changes made to other entries by other processes.
+  pub fn set(&mut self, name: &str, value: String) -> Result<()> {
+    self.locked(|vault| {
+      vault.entries.insert(name.to_owned(), value);
+      vault.save()
+    })
+  }
+
+  /// Removes `name`, keeping any changes made to other entries by other processes.
+  pub fn remove(&mut self, name: &str) -> Result<()> {
+    self.locked(|vault| {
+      if vault.entries.remove(name).is_some() {
+        vault.save()?;
+      }
+      Ok(())
+    })
+  }
+
+  /// Re-encrypts the vault with a key derived from a new secret.
+  pub fn rotate_key(&mut self, key_source: &KeySource) -> Result<()> {
+    self.locked(|vault| {
+      vault.salt = random_bytes(SALT_LEN);
+      vault.key = key_source.derive_key(&vault.salt)?;
+      vault.save()
+    })
+  }
+
+  /// Runs `f` with the vault file locked against other processes, after reading the
+  /// entries they may have changed. Other vaults wait for `f` to finish, so it can
+  /// read and then write an entry without another process changing it in between.
+  pub fn locked<T, E, F>(&mut self, f: F) -> Result<T, E>
+  where
+    F: FnOnce(&mut Vault) -> Result<T, E>,
+    E: From<anyhow::Error>,
+  {
+    // Already locked by an enclosing call.
+    if self.lock_file.is_some() {
+      return f(self);
+    }
+
+    self.lock_file = Some(lock_file(&self.path.with_extension("lock"))?);
+    let result = self.reload().map_err(E::from).and_then(|()| f(self));
+    // Closing the file releases the lock.
+    self.lock_file = None;
+    result
+  }
+
+  /// Replaces the entries with the ones on disk, if the vault has been written.
+  fn reload(&mut self) -> Result<()> {
+    if let Some(file) = read_file(&self.path)? {
+      anyhow::ensure!(
+        base64::decode(&file.salt)? == self.salt,
+        "The credential vault was re-encrypted with another key. Restart with the new key."
+      );
+      self.entries = decrypt(&file, &self.key)?;
+    }
+    Ok(())
+  }
+
+  fn save(&self) -> Result<()> {
+    let nonce = random_bytes(NONCE_LEN);
+    let plaintext = serde_json::to_vec(&self.entries)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+      .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+      .map_err(|_| anyhow!("Unable to encrypt the credential vault"))?;
+
+    let file = VaultFile {
+      version: VAULT_VERSION,
+      salt: base64::encode(&self.salt),
+      nonce: base64::encode(&nonce),
+      ciphertext: base64::encode(&ciphertext),
+    };
+
+    write_private_file(&self.path, &serde_json::to_vec_pretty(&file)?)
+  }
+}
+
+fn read_file(path: &Path) -> Result<Option<VaultFile>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+
+  let file: VaultFile = serde_json::from_str(&read_to_string(path)?)?;
+  anyhow::ensure!(
+    file.version == VAULT_VERSION,
+    "Unsupported vault version {}",
+    file.version
+  );
+  Ok(Some(file))
+}
+
+fn decrypt(file: &VaultFile, key: &[u8; 32]) -> Result<HashMap<String, String>> {
+  let nonce = base64::decode(&file.nonce)?;
+  let ciphertext = base64::decode(&file.ciphertext)?;
+
+  let plaintext = ChaCha20Poly1305::new(Key::from_slice(key))
+    .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+    .map_err(|_| anyhow!("Unable to decrypt the credential vault. Is the key correct?"))?;
+  Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// Re-encrypts the vault, unlocking it with the current key and locking it with
+/// the key given by `FITSYNC_NEW_VAULT_PASSPHRASE` or `FITSYNC_NEW_VAULT_KEY_FILE`.
+pub fn rotate_key(project_dirs: &ProjectDirs) -> Result<()> {
+  let new_key_source = KeySource::from_env(NEW_PASSPHRASE_VAR, NEW_KEY_FILE_VAR)?;
+  let vault = Vault::open(project_dirs)?;
+
+  vault.lock().unwrap().rotate_key(&new_key_source)?;
+  info!(
+    "Vault key rotated. Use the new key in {} or {} from now on.",
+    PASSPHRASE_VAR, KEY_FILE_VAR
+  );
+
+  Ok(())
+}
+
+/// Writes a file that only the owning user can read. The contents are written to a
+/// temporary file first so that a failed write can't leave a truncated vault behind.
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+  std::fs::create_dir_all(path.parent().unwrap())?;
+
+  let tmp_path = path.with_extension("tmp");
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(&tmp_path)?;
+  // The mode above only applies when the file is created.
+  file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+  file.write_all(contents)?;
+  file.sync_all()?;
+
+  std::fs::rename(&tmp_path, path)?;
+
+  Ok(())
+}
+
+/// Opens `path` and waits for an exclusive lock on it, which is held until the file
+/// is closed.
+fn lock_file(path: &Path) -> Result<File> {
+  std::fs::create_dir_all(path.parent().unwrap())?;
+
+  let file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .mode(0o600)
+    .open(path)?;
+  // SAFETY: the descriptor stays open for the duration of the call.
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+    return Err(anyhow!(
+      "Unable to lock {:?}: {}",
+      path,
+      std::io::Error::last_os_error()
+    ));
+  }
+  Ok(file)
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+  let mut bytes = vec![0u8; len];
+  rand::thread_rng().fill_bytes(&mut bytes);
+  bytes
+}
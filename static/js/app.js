@@ -7,22 +7,45 @@ const Main = {
     }
   },
   mounted() {
-    console.log("Requesting authstate")
-    axios.get('/api/authstate').then(response => {
-      this.loading = false
-      this.auth_state = response.data
-
-      if (!this?.auth_state?.fitbit?.has_token) {
-        const client_id = encodeURIComponent(this.auth_state.fitbit.client_id)
-        const redirect_uri = encodeURIComponent(this.auth_state.fitbit.redirect_uri)
-        const scopes = encodeURIComponent(this.auth_state.fitbit.scopes)
-        window.location.href = `https://www.fitbit.com/oauth2/authorize?response_type=code&client_id=${client_id}&redirect_uri=${redirect_uri}&scope=${scopes}`
-      }
-    })
+    this.loadAuthState()
+  },
+  methods: {
+    loadAuthState() {
+      console.log("Requesting authstate")
+      axios.get('/api/authstate').then(response => {
+        this.loading = false
+        this.auth_state = response.data
+      })
+    },
+    connectFitbit(account) {
+      window.location.href = account.start_url
+    },
+    disconnectFitbit(account) {
+      axios.post('/api/auth/fitbit/revoke', null, { params: { account: account.account } })
+        .then(() => this.loadAuthState())
+    },
+    connectGoogle() {
+      window.location.href = this.auth_state.google.start_url
+    },
+    disconnectGoogle() {
+      axios.post('/api/auth/google/revoke').then(() => this.loadAuthState())
+    }
   },
   template: `
-  <div><span>Fitbit: </span><status :ok="auth_state?.fitbit?.has_token" /></div>
-  <div><span>Google: </span><status :ok="auth_state?.google?.has_token" /></div>
+  <div v-for="account in auth_state?.fitbit">
+    <span>Fitbit ({{ account.account }}): </span><status :ok="account.has_token" />
+    <button v-if="account.has_token" @click="disconnectFitbit(account)">Disconnect</button>
+    <button v-else @click="connectFitbit(account)">Connect</button>
+    <span v-if="account.has_token && account.missing_scopes.length">
+      Missing access to {{ account.missing_scopes.join(", ") }}.
+      <button @click="connectFitbit(account)">Reconnect</button>
+    </span>
+  </div>
+  <div v-if="auth_state?.google">
+    <span>Google: </span><status :ok="auth_state.google.has_token" />
+    <button v-if="auth_state.google.has_token" @click="disconnectGoogle">Disconnect</button>
+    <button v-else @click="connectGoogle">Connect</button>
+  </div>
   `
 }
 
@@ -38,4 +61,4 @@ app.component('status', {
   `
 })
 
-app.mount('#app')
\ No newline at end of file
+app.mount('#app')
@@ -0,0 +1,68 @@
+//! Checks the authorization code flow against the mock token endpoint.
+
+mod support;
+
+use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
+use support::{query_param, TestEnv};
+
+static TOKEN_PATH: &str = "/oauth2/token";
+
+#[test]
+fn rejects_callback_with_wrong_state() {
+  let env = TestEnv::new("auth-wrong-state");
+  let mut oauth = env.fitbit_oauth();
+  let url = oauth.authorize_url();
+
+  assert!(oauth
+    .obtain_tokens("mock-code".to_owned(), "forged-state".to_owned())
+    .is_err());
+  assert_eq!(env.mock.requests_to("POST", TOKEN_PATH), 0);
+
+  // The authorization is still pending, so the real callback can complete it.
+  let state = query_param(&url, "state");
+  assert!(oauth.is_pending(&state));
+  oauth.obtain_tokens("mock-code".to_owned(), state).unwrap();
+  assert!(oauth.has_secret());
+}
+
+#[test]
+fn rejects_callback_without_pending_authorization() {
+  let env = TestEnv::new("auth-not-pending");
+  let mut oauth = env.fitbit_oauth();
+
+  assert!(oauth
+    .obtain_tokens("mock-code".to_owned(), "some-state".to_owned())
+    .is_err());
+  assert_eq!(env.mock.requests_to("POST", TOKEN_PATH), 0);
+
+  // A state can only be used once.
+  let state = query_param(&oauth.authorize_url(), "state");
+  oauth
+    .obtain_tokens("mock-code".to_owned(), state.clone())
+    .unwrap();
+  assert!(oauth.obtain_tokens("mock-code".to_owned(), state).is_err());
+  assert_eq!(env.mock.requests_to("POST", TOKEN_PATH), 1);
+}
+
+#[test]
+fn sends_pkce_verifier_for_challenge() {
+  let env = TestEnv::new("auth-pkce");
+  let mut oauth = env.fitbit_oauth();
+  let url = oauth.authorize_url();
+
+  assert_eq!(query_param(&url, "code_challenge_method"), "S256");
+  let challenge = query_param(&url, "code_challenge");
+  oauth
+    .obtain_tokens("mock-code".to_owned(), query_param(&url, "state"))
+    .unwrap();
+
+  let bodies = env.mock.form_bodies("POST", TOKEN_PATH);
+  assert_eq!(bodies.len(), 1);
+  assert_eq!(bodies[0]["grant_type"], "authorization_code");
+  assert_eq!(bodies[0]["code"], "mock-code");
+  let verifier = PkceCodeVerifier::new(bodies[0]["code_verifier"].clone());
+  assert_eq!(
+    PkceCodeChallenge::from_code_verifier_sha256(&verifier).as_str(),
+    challenge
+  );
+}
@@ -0,0 +1,106 @@
+//! Checks when scheduled syncs run.
+
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+use fitsync::schedule::{QuietHours, Schedule};
+use serde_json::json;
+
+fn at(hour: u32, minute: u32) -> DateTime<Local> {
+  Local.ymd(2021, 1, 1).and_hms(hour, minute, 0)
+}
+
+fn with_quiet_hours(start: (u32, u32), end: (u32, u32)) -> Schedule {
+  Schedule {
+    quiet_hours: Some(QuietHours {
+      start: NaiveTime::from_hms(start.0, start.1, 0),
+      end: NaiveTime::from_hms(end.0, end.1, 0),
+    }),
+    ..Schedule::default()
+  }
+}
+
+#[test]
+fn quiet_hours_within_a_day() {
+  let schedule = with_quiet_hours((9, 0), (17, 0));
+
+  assert!(!schedule.is_quiet(at(8, 59)));
+  assert!(schedule.is_quiet(at(9, 0)));
+  assert!(schedule.is_quiet(at(16, 59)));
+  assert!(!schedule.is_quiet(at(17, 0)));
+}
+
+#[test]
+fn quiet_hours_past_midnight() {
+  let schedule = with_quiet_hours((23, 0), (7, 0));
+
+  assert!(!schedule.is_quiet(at(22, 59)));
+  assert!(schedule.is_quiet(at(23, 0)));
+  assert!(schedule.is_quiet(at(0, 0)));
+  assert!(schedule.is_quiet(at(6, 59)));
+  assert!(!schedule.is_quiet(at(7, 0)));
+  assert!(!schedule.is_quiet(at(12, 0)));
+}
+
+#[test]
+fn no_quiet_hours_by_default() {
+  assert!(!Schedule::default().is_quiet(at(3, 0)));
+}
+
+#[test]
+fn interval_runs_after_minutes() {
+  let schedule: Schedule = serde_json::from_value(json!({ "interval_minutes": 30 })).unwrap();
+
+  assert_eq!(schedule.next_after(at(10, 0)).unwrap(), at(10, 30));
+  assert_eq!(
+    Schedule::default().next_after(at(10, 0)).unwrap(),
+    at(10, 15)
+  );
+}
+
+#[test]
+fn cron_runs_at_next_matching_time() {
+  let schedule: Schedule = serde_json::from_value(json!({
+    "interval_minutes": 0,
+    "cron": "0 30 2 * * *",
+  }))
+  .unwrap();
+
+  assert_eq!(schedule.next_after(at(1, 0)).unwrap(), at(2, 30));
+  assert_eq!(
+    schedule.next_after(at(3, 0)).unwrap(),
+    at(2, 30) + Duration::days(1)
+  );
+}
+
+#[test]
+fn jitter_delays_by_at_most_jitter_seconds() {
+  let schedule: Schedule = serde_json::from_value(json!({ "jitter_seconds": 60 })).unwrap();
+
+  for _ in 0..100 {
+    let next_run = schedule.next_run_after(at(10, 0)).unwrap();
+    assert!(next_run >= at(10, 15));
+    assert!(next_run <= at(10, 16));
+  }
+  assert_eq!(
+    Schedule::default().next_run_after(at(10, 0)).unwrap(),
+    at(10, 15)
+  );
+}
+
+#[test]
+fn validates_interval_and_cron() {
+  assert!(Schedule::default().validate().is_ok());
+
+  let no_interval: Schedule = serde_json::from_value(json!({ "interval_minutes": 0 })).unwrap();
+  assert!(no_interval.validate().is_err());
+
+  let cron_only: Schedule = serde_json::from_value(json!({
+    "interval_minutes": 0,
+    "cron": "0 0 * * * *",
+  }))
+  .unwrap();
+  assert!(cron_only.validate().is_ok());
+
+  let bad_cron: Schedule = serde_json::from_value(json!({ "cron": "every day" })).unwrap();
+  assert!(bad_cron.validate().is_err());
+  assert!(bad_cron.next_after(at(10, 0)).is_err());
+}
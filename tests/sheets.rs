@@ -0,0 +1,290 @@
+//! Drives `SheetsClient` against the mock server's Sheets endpoints.
+
+mod support;
+
+use fitsync::sync::SyncSession;
+use serde_json::json;
+use support::{MockResponse, TestEnv};
+
+static SPREADSHEET: &str = "mock-spreadsheet";
+static VALUES_PATH: &str = "/v4/spreadsheets/mock-spreadsheet/values/";
+static BATCH_UPDATE_PATH: &str = "/v4/spreadsheets/mock-spreadsheet/values:batchUpdate";
+
+#[test]
+fn appends_rows() {
+  let env = TestEnv::new("sheets-append");
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  let client = env.authorize_google().unwrap();
+
+  client
+    .append_rows(
+      SPREADSHEET,
+      "Weight",
+      vec![vec![json!("2016-01-03"), json!(80.1)]],
+    )
+    .unwrap();
+
+  assert_eq!(
+    env.mock.requests().last().unwrap(),
+    "POST /v4/spreadsheets/mock-spreadsheet/values/'Weight':append\
+     ?valueInputOption=RAW&insertDataOption=INSERT_ROWS"
+  );
+  assert_eq!(
+    env.mock.json_bodies("POST", VALUES_PATH),
+    vec![json!({ "values": [["2016-01-03", 80.1]] })]
+  );
+}
+
+#[test]
+fn skips_rows_whose_key_is_already_in_the_sheet() {
+  let env = TestEnv::new("sheets-skip-existing");
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values.json"),
+  );
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  let client = env.authorize_google().unwrap();
+
+  client
+    .append_new_rows(
+      SPREADSHEET,
+      "Weight_steps",
+      vec![
+        vec![json!("2016-01-02"), json!(11873.0)],
+        vec![json!("2016-01-03"), json!(0.0)],
+      ],
+    )
+    .unwrap();
+
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /v4/spreadsheets/mock-spreadsheet/values/'Weight_steps'!A:A".to_owned()));
+  assert_eq!(
+    env.mock.json_bodies("POST", VALUES_PATH),
+    vec![json!({ "values": [["2016-01-03", 0.0]] })]
+  );
+
+  // Nothing is appended when every key is present.
+  client
+    .append_new_rows(
+      SPREADSHEET,
+      "Weight_steps",
+      vec![vec![json!("2016-01-01"), json!(4052.0)]],
+    )
+    .unwrap();
+  assert_eq!(env.mock.requests_to("POST", VALUES_PATH), 1);
+}
+
+#[test]
+fn updates_rows_whose_key_is_already_in_the_sheet() {
+  let env = TestEnv::new("sheets-update-existing");
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values.json"),
+  );
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  env.mock.on(
+    "POST",
+    BATCH_UPDATE_PATH,
+    MockResponse::fixture(200, "sheets_batch_update.json"),
+  );
+  let client = env.authorize_google().unwrap();
+
+  client
+    .update_or_append_rows(
+      SPREADSHEET,
+      "Weight_steps",
+      vec![
+        vec![json!("2016-01-02"), json!(12000.0)],
+        vec![json!("2016-01-03"), json!(0.0)],
+      ],
+    )
+    .unwrap();
+
+  // The header is row 1, so 2016-01-02 is row 3.
+  assert_eq!(
+    env.mock.json_bodies("POST", BATCH_UPDATE_PATH),
+    vec![json!({
+      "valueInputOption": "RAW",
+      "data": [{ "range": "'Weight_steps'!A3", "values": [["2016-01-02", 12000.0]] }],
+    })]
+  );
+  assert_eq!(
+    env.mock.json_bodies("POST", VALUES_PATH),
+    vec![json!({ "values": [["2016-01-03", 0.0]] })]
+  );
+}
+
+#[test]
+fn refreshes_rejected_token_and_retries() {
+  let env = TestEnv::new("sheets-refresh");
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(401, "error_sheets_unauthenticated.json"),
+  );
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values.json"),
+  );
+  let client = env.authorize_google().unwrap();
+
+  let keys = client
+    .get_first_column(SPREADSHEET, "Weight_steps")
+    .unwrap();
+
+  assert_eq!(keys, vec!["dateTime", "2016-01-01", "2016-01-02"]);
+  // One request for the authorization code, and one to refresh.
+  assert_eq!(env.mock.requests_to("POST", "/token"), 2);
+  assert_eq!(env.mock.requests_to("GET", VALUES_PATH), 2);
+}
+
+#[test]
+fn writes_synced_values_without_widening_them() {
+  let env = TestEnv::new("sheets-sync-values");
+  env.set_destinations(json!([{
+    "id": "sheet",
+    "kind": { "GoogleSheet": { "spreadsheet_id": SPREADSHEET, "sheet": "Weight" } },
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/body/weight/date/",
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values_empty.json"),
+  );
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  env.authorize().unwrap();
+  let client = env.authorize_google().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations_with_sheets(client);
+  SyncSession::start(&destinations, &accounts)
+    .sync_all()
+    .unwrap();
+
+  // Each yearly range gets the same fixture, so check the first.
+  assert_eq!(
+    env.mock.json_bodies("POST", VALUES_PATH)[0],
+    json!({
+      "values": [
+        ["2016-01-01", 80.5],
+        ["2016-01-02", 80.5],
+        ["2016-01-03", 80.1],
+        ["2016-01-04", 79.8],
+      ]
+    })
+  );
+}
+
+#[test]
+fn writes_weight_logs_without_widening_them() {
+  let env = TestEnv::new("sheets-weight-logs");
+  env.set_destinations(json!([{
+    "id": "sheet",
+    "kind": { "GoogleSheet": { "spreadsheet_id": SPREADSHEET, "sheet": "Weight" } },
+    "weight_mode": "logs",
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/body/log/weight/date/",
+    MockResponse::fixture(200, "weight_logs.json"),
+  );
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values_empty.json"),
+  );
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  env.authorize().unwrap();
+  let client = env.authorize_google().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations_with_sheets(client);
+  SyncSession::start(&destinations, &accounts)
+    .sync_all()
+    .unwrap();
+
+  assert_eq!(
+    env.mock.json_bodies("POST", VALUES_PATH)[0],
+    json!({
+      "values": [
+        ["1451806200000", "2016-01-03 07:30:00", 80.1, 24.12, 22.5, "Aria"],
+        ["1451893500000", "2016-01-04 07:45:00", 79.8, 23.93, null, "API"],
+      ]
+    })
+  );
+}
+
+#[test]
+fn writes_each_daily_log_table_once_per_sync() {
+  let env = TestEnv::new("sheets-daily-logs");
+  env.set_destinations(json!([{
+    "id": "sheet",
+    "kind": { "GoogleSheet": { "spreadsheet_id": SPREADSHEET, "sheet": "Weight" } },
+    "body_metrics": [],
+    "nutrition": true,
+    "food_logs": true,
+    "water_logs": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/foods/log/date/",
+    MockResponse::fixture(200, "food_log.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/foods/log/water/date/",
+    MockResponse::fixture(200, "water_logs.json"),
+  );
+  env.mock.on(
+    "GET",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_values_empty.json"),
+  );
+  env.mock.on(
+    "POST",
+    VALUES_PATH,
+    MockResponse::fixture(200, "sheets_append.json"),
+  );
+  env.authorize().unwrap();
+  let client = env.authorize_google().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations_with_sheets(client);
+  SyncSession::start(&destinations, &accounts)
+    .sync_all()
+    .unwrap();
+
+  // 91 days are fetched, and each of the three tables is read and appended once.
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/foods/log/date/"), 91);
+  assert_eq!(env.mock.requests_to("GET", VALUES_PATH), 3);
+  assert_eq!(env.mock.requests_to("POST", VALUES_PATH), 3);
+}
@@ -0,0 +1,467 @@
+//! A mock Fitbit server for integration tests, and helpers to set up the state
+//! that `SyncSession` needs against it.
+
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::read_to_string;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use fitsync::account::{Account, Accounts, DEFAULT_ACCOUNT};
+use fitsync::auth::OAuthClient;
+use fitsync::config::Config;
+use fitsync::destination::Destinations;
+use fitsync::sheets::{self, SheetsClient};
+use fitsync::sync::SyncSession;
+use fitsync::vault::{SharedVault, Vault};
+use oauth2::url::{form_urlencoded, Url};
+use serde_json::{json, Value};
+use tiny_http::{Header, Request, Response, Server};
+
+/// A canned response, usually read from `tests/fixtures`.
+#[derive(Clone)]
+pub struct MockResponse {
+  status: u16,
+  body: String,
+  headers: Vec<(String, String)>,
+  broken: bool,
+}
+
+impl MockResponse {
+  pub fn fixture(status: u16, name: &str) -> Self {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+      .join("tests")
+      .join("fixtures")
+      .join(name);
+    MockResponse {
+      status,
+      body: read_to_string(&path).unwrap_or_else(|e| panic!("Reading {:?}: {}", path, e)),
+      headers: vec![],
+      broken: false,
+    }
+  }
+
+  /// A reply that isn't HTTP, which the client sees as a failed connection.
+  pub fn broken_connection() -> Self {
+    MockResponse {
+      status: 0,
+      body: String::new(),
+      headers: vec![],
+      broken: true,
+    }
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.to_owned(), value.to_owned()));
+    self
+  }
+}
+
+/// A request received by the mock server.
+struct RecordedRequest {
+  /// "<method> <path and query>".
+  summary: String,
+  headers: Vec<(String, String)>,
+  body: String,
+}
+
+/// Responses for the requests whose path starts with `path_prefix`. They're served
+/// in order, and the last one is repeated once the others have been used.
+struct Route {
+  method: String,
+  path_prefix: String,
+  responses: VecDeque<MockResponse>,
+}
+
+impl Route {
+  fn next_response(&mut self) -> MockResponse {
+    if self.responses.len() > 1 {
+      self.responses.pop_front().unwrap()
+    } else {
+      self.responses[0].clone()
+    }
+  }
+}
+
+/// An HTTP server on a random local port that stands in for `api.fitbit.com`, and
+/// for the Google endpoints under `/v4` and `/token`. Requests that don't match a
+/// route get a 404 like Fitbit's.
+pub struct MockFitbit {
+  server: Arc<Server>,
+  routes: Arc<Mutex<Vec<Route>>>,
+  requests: Arc<Mutex<Vec<RecordedRequest>>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl MockFitbit {
+  pub fn start() -> Self {
+    let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+    let routes = Arc::new(Mutex::new(Vec::<Route>::new()));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let thread = {
+      let server = server.clone();
+      let routes = routes.clone();
+      let requests = requests.clone();
+      std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+          let summary = format!("{} {}", request.method(), request.url());
+          let headers = request
+            .headers()
+            .iter()
+            .map(|h| (h.field.to_string(), h.value.to_string()))
+            .collect();
+          let mut body = String::new();
+          let _ = request.as_reader().read_to_string(&mut body);
+          requests.lock().unwrap().push(RecordedRequest {
+            summary,
+            headers,
+            body,
+          });
+          let response = Self::find_response(&routes, &request);
+          Self::respond(request, response);
+        }
+      })
+    };
+
+    MockFitbit {
+      server,
+      routes,
+      requests,
+      thread: Some(thread),
+    }
+  }
+
+  pub fn base_url(&self) -> String {
+    format!("http://{}", self.server.server_addr().to_ip().unwrap())
+  }
+
+  /// Adds a response for requests with `method` whose path starts with `path_prefix`.
+  pub fn on(&self, method: &str, path_prefix: &str, response: MockResponse) {
+    let mut routes = self.routes.lock().unwrap();
+    match routes
+      .iter_mut()
+      .find(|r| r.method == method && r.path_prefix == path_prefix)
+    {
+      Some(route) => route.responses.push_back(response),
+      None => routes.push(Route {
+        method: method.to_owned(),
+        path_prefix: path_prefix.to_owned(),
+        responses: vec![response].into(),
+      }),
+    }
+  }
+
+  /// The requests received so far, as "<method> <path and query>".
+  pub fn requests(&self) -> Vec<String> {
+    self
+      .requests
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|r| r.summary.clone())
+      .collect()
+  }
+
+  /// The bodies of the requests with `method` whose path starts with `path_prefix`,
+  /// parsed as JSON.
+  pub fn json_bodies(&self, method: &str, path_prefix: &str) -> Vec<Value> {
+    let prefix = format!("{} {}", method, path_prefix);
+    self
+      .requests
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|r| r.summary.starts_with(&prefix))
+      .map(|r| serde_json::from_str(&r.body).unwrap())
+      .collect()
+  }
+
+  /// The bodies of the requests with `method` whose path starts with `path_prefix`,
+  /// parsed as form fields.
+  pub fn form_bodies(&self, method: &str, path_prefix: &str) -> Vec<HashMap<String, String>> {
+    let prefix = format!("{} {}", method, path_prefix);
+    self
+      .requests
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|r| r.summary.starts_with(&prefix))
+      .map(|r| {
+        form_urlencoded::parse(r.body.as_bytes())
+          .into_owned()
+          .collect()
+      })
+      .collect()
+  }
+
+  /// The value of the `name` header in each request with `method` whose path
+  /// starts with `path_prefix`.
+  pub fn header_values(&self, method: &str, path_prefix: &str, name: &str) -> Vec<Option<String>> {
+    let prefix = format!("{} {}", method, path_prefix);
+    self
+      .requests
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|r| r.summary.starts_with(&prefix))
+      .map(|r| {
+        r.headers
+          .iter()
+          .find(|(field, _)| field.eq_ignore_ascii_case(name))
+          .map(|(_, value)| value.clone())
+      })
+      .collect()
+  }
+
+  pub fn requests_to(&self, method: &str, path_prefix: &str) -> usize {
+    let prefix = format!("{} {}", method, path_prefix);
+    self
+      .requests()
+      .iter()
+      .filter(|r| r.starts_with(&prefix))
+      .count()
+  }
+
+  fn find_response(routes: &Mutex<Vec<Route>>, request: &Request) -> MockResponse {
+    let method = request.method().to_string();
+    let mut routes = routes.lock().unwrap();
+    match routes
+      .iter_mut()
+      .find(|r| r.method == method && request.url().starts_with(&r.path_prefix))
+    {
+      Some(route) => route.next_response(),
+      None => MockResponse::fixture(404, "error_not_found.json"),
+    }
+  }
+
+  fn respond(request: Request, response: MockResponse) {
+    if response.broken {
+      let mut writer = request.into_writer();
+      let _ = writer.write_all(b"not http\r\n\r\n");
+      let _ = writer.flush();
+      return;
+    }
+
+    let mut http_response = Response::from_string(response.body)
+      .with_status_code(response.status)
+      .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    for (name, value) in response.headers.iter() {
+      http_response.add_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+    }
+    request.respond(http_response).unwrap();
+  }
+}
+
+impl Drop for MockFitbit {
+  fn drop(&mut self) {
+    self.server.unblock();
+    if let Some(thread) = self.thread.take() {
+      thread.join().unwrap();
+    }
+  }
+}
+
+static SETUP: Once = Once::new();
+
+/// Points the config, data and cache directories at a scratch directory for this
+/// test process, and sets the vault passphrase. Must be called before anything
+/// else reads the environment.
+fn setup_environment() {
+  SETUP.call_once(|| {
+    let root = std::env::temp_dir().join(format!("fitsync-tests-{}", std::process::id()));
+    std::env::set_var("XDG_CONFIG_HOME", root.join("config"));
+    std::env::set_var("XDG_DATA_HOME", root.join("data"));
+    std::env::set_var("XDG_CACHE_HOME", root.join("cache"));
+    std::env::set_var("FITSYNC_VAULT_PASSPHRASE", "test passphrase");
+  });
+}
+
+/// Everything a test needs to sync to a CSV file from the mock server. Each test
+/// uses its own directories, named after it.
+pub struct TestEnv {
+  pub mock: MockFitbit,
+  pub project_dirs: ProjectDirs,
+  pub vault: SharedVault,
+  pub config: Config,
+  pub csv_path: PathBuf,
+}
+
+impl TestEnv {
+  pub fn new(name: &str) -> Self {
+    Self::with_profile(name, "profile.json")
+  }
+
+  /// Like `new`, with the user's profile read from the `profile` fixture.
+  pub fn with_profile(name: &str, profile: &str) -> Self {
+    setup_environment();
+
+    let mock = MockFitbit::start();
+    mock.on(
+      "POST",
+      "/oauth2/token",
+      MockResponse::fixture(200, "token.json"),
+    );
+    mock.on(
+      "GET",
+      "/1/user/-/profile.json",
+      MockResponse::fixture(200, profile),
+    );
+
+    let project_dirs = ProjectDirs::from("org", "dubh", &format!("fitsync-{}", name)).unwrap();
+    for dir in [
+      project_dirs.config_dir(),
+      project_dirs.data_dir(),
+      project_dirs.cache_dir(),
+    ]
+    .iter()
+    {
+      if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+      }
+      std::fs::create_dir_all(dir).unwrap();
+    }
+
+    let csv_path = project_dirs.data_dir().join("weight.csv");
+
+    let config: Config = serde_json::from_value(json!({
+      "auth": {
+        "fitbit": { "id": "client-id", "secret": "client-secret" },
+        "google": { "id": "google-client-id", "secret": "google-client-secret" },
+      },
+      "fitbit_base_url": mock.base_url(),
+      "sheets_base_url": format!("{}/v4", mock.base_url()),
+      "google_oauth_base_url": mock.base_url(),
+    }))
+    .unwrap();
+
+    let vault = Vault::open(&project_dirs).unwrap();
+
+    let env = TestEnv {
+      mock,
+      project_dirs,
+      vault,
+      config,
+      csv_path,
+    };
+    env.set_destinations(json!([{
+      "id": "csv",
+      "kind": { "CsvFile": { "path": env.csv_path } },
+    }]));
+    env
+  }
+
+  /// Replaces destinations.json with `destinations`.
+  pub fn set_destinations(&self, destinations: Value) {
+    std::fs::write(
+      self.project_dirs.config_dir().join("destinations.json"),
+      json!({ "destinations": destinations }).to_string(),
+    )
+    .unwrap();
+  }
+
+  /// An OAuth client for the default account, with no authorization in progress.
+  pub fn fitbit_oauth(&self) -> OAuthClient {
+    Account::fitbit_oauth(
+      DEFAULT_ACCOUNT,
+      &self.config,
+      &self.project_dirs,
+      self.vault.clone(),
+    )
+    .unwrap()
+  }
+
+  /// Authorizes the default account against the mock token endpoint, as if the
+  /// user had allowed access.
+  pub fn authorize(&self) -> Result<()> {
+    let mut oauth = self.fitbit_oauth();
+    let url = oauth.authorize_url();
+    let state = query_param(&url, "state");
+    oauth.obtain_tokens("mock-code".to_owned(), state)
+  }
+
+  /// A Sheets client for the mock server, authorized like `authorize` does for
+  /// Fitbit.
+  pub fn authorize_google(&self) -> Result<SheetsClient> {
+    self
+      .mock
+      .on("POST", "/token", MockResponse::fixture(200, "token.json"));
+    let mut oauth =
+      sheets::google_oauth(&self.config, &self.project_dirs, self.vault.clone())?.unwrap();
+    let url = oauth.authorize_url();
+    let state = query_param(&url, "state");
+    oauth.obtain_tokens("mock-code".to_owned(), state)?;
+    Ok(SheetsClient::new(oauth, &self.config.sheets_base_url))
+  }
+
+  pub fn accounts(&self) -> Accounts {
+    Accounts::load(&self.config, &self.project_dirs, &self.vault).unwrap()
+  }
+
+  pub fn destinations(&self) -> Mutex<Destinations> {
+    Mutex::new(Destinations::load(&self.project_dirs, None).unwrap())
+  }
+
+  /// Like `destinations`, with Google Sheets destinations written through `client`.
+  pub fn destinations_with_sheets(&self, client: SheetsClient) -> Mutex<Destinations> {
+    Mutex::new(Destinations::load(&self.project_dirs, Some(Arc::new(client))).unwrap())
+  }
+
+  /// Syncs every destination, returning the accounts and destinations that were
+  /// used so that their state can be checked.
+  pub fn sync_all(&self) -> (Accounts, Mutex<Destinations>) {
+    let (accounts, destinations, result) = self.try_sync_all();
+    result.unwrap();
+    (accounts, destinations)
+  }
+
+  /// Like `sync_all`, for syncs that are expected to fail.
+  pub fn try_sync_all(&self) -> (Accounts, Mutex<Destinations>, Result<()>) {
+    let accounts = self.accounts();
+    let destinations = self.destinations();
+    let result = SyncSession::start(&destinations, &accounts).sync_all();
+    (accounts, destinations, result)
+  }
+
+  /// The rows of the CSV destination, after the header.
+  pub fn csv_rows(&self) -> Vec<Value> {
+    read_csv(&self.csv_path)
+  }
+}
+
+/// The value of the `name` query parameter in `url`.
+pub fn query_param(url: &Url, name: &str) -> String {
+  url
+    .query_pairs()
+    .find(|(field, _)| field == name)
+    .map(|(_, value)| value.into_owned())
+    .unwrap_or_else(|| panic!("No {} in {}", name, url))
+}
+
+/// The rows of a CSV file after the header, each as an array of strings.
+pub fn read_csv(path: &Path) -> Vec<Value> {
+  let mut reader = csv::Reader::from_path(path).unwrap();
+  reader
+    .records()
+    .map(|r| json!(r.unwrap().iter().collect::<Vec<_>>()))
+    .collect()
+}
+
+impl Drop for TestEnv {
+  fn drop(&mut self) {
+    for dir in [
+      self.project_dirs.config_dir(),
+      self.project_dirs.data_dir(),
+      self.project_dirs.cache_dir(),
+    ]
+    .iter()
+    {
+      let _ = std::fs::remove_dir_all(dir);
+    }
+  }
+}
@@ -0,0 +1,1206 @@
+//! Drives `SyncSession` end-to-end against the mock Fitbit server.
+
+mod support;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use fitsync::account::{Accounts, DEFAULT_ACCOUNT};
+use fitsync::fitbit::{FitbitError, GetWeightLogsRequest, TimePeriod};
+use fitsync::sync::SyncSession;
+use fitsync::vault::Vault;
+use serde_json::json;
+use support::{read_csv, MockResponse, TestEnv};
+
+static BODY_WEIGHT_PATH: &str = "/1/user/-/body/weight/date/";
+
+#[test]
+fn syncs_body_weight_to_csv() {
+  let env = TestEnv::new("syncs-body-weight");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  let (_, destinations) = env.sync_all();
+
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /1/user/-/body/weight/date/2016-01-01/2016-12-31.json".to_owned()));
+  // Unchanged values are dropped.
+  assert_eq!(
+    env.csv_rows(),
+    vec![
+      json!(["2016-01-01", "80.5"]),
+      json!(["2016-01-03", "80.1"]),
+      json!(["2016-01-04", "79.8"]),
+    ]
+  );
+
+  let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+  assert_eq!(statuses[0]["consecutive_failures"], 0);
+  assert!(statuses[0]["last_synced"].is_string());
+}
+
+#[test]
+fn syncs_selected_body_metrics_to_separate_files() {
+  let env = TestEnv::new("syncs-body-metrics");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": ["weight", "fat"],
+  }]));
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/body/fat/date/",
+    MockResponse::fixture(200, "body_fat.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert_eq!(env.csv_rows().len(), 3);
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_fat.csv")),
+    vec![json!(["2016-01-01", "22.5"]), json!(["2016-01-02", "22.3"])]
+  );
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/body/bmi/"), 0);
+}
+
+#[test]
+fn first_sync_starts_from_destination_start_date() {
+  let env = TestEnv::new("syncs-from-start-date");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "start_date": "2019-06-01",
+  }]));
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let requests = env.mock.requests();
+  assert!(requests.contains(&format!(
+    "GET {}2019-06-01/2020-05-31.json",
+    BODY_WEIGHT_PATH
+  )));
+  assert!(!requests
+    .iter()
+    .any(|r| r.starts_with(&format!("GET {}2016-", BODY_WEIGHT_PATH))));
+}
+
+#[test]
+fn backfills_a_date_range_without_changing_last_sync() {
+  let env = TestEnv::new("backfill");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations();
+  SyncSession::start(&destinations, &accounts)
+    .backfill(
+      "csv",
+      NaiveDate::from_ymd(2017, 3, 1),
+      Some(NaiveDate::from_ymd(2017, 3, 10)),
+    )
+    .unwrap();
+
+  assert_eq!(env.mock.requests_to("GET", BODY_WEIGHT_PATH), 1);
+  assert!(env.mock.requests().contains(&format!(
+    "GET {}2017-03-01/2017-03-10.json",
+    BODY_WEIGHT_PATH
+  )));
+  assert_eq!(env.csv_rows().len(), 3);
+  assert_eq!(destinations.lock().unwrap().last_synced("csv"), None);
+
+  assert!(SyncSession::start(&destinations, &accounts)
+    .backfill(
+      "missing",
+      NaiveDate::from_ymd(2017, 3, 1),
+      Some(NaiveDate::from_ymd(2017, 3, 10)),
+    )
+    .is_err());
+}
+
+#[test]
+fn syncs_activity_time_series() {
+  let env = TestEnv::new("syncs-activity");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "activity_metrics": ["steps", "distance"],
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/steps/date/",
+    MockResponse::fixture(200, "activities_steps.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/distance/date/",
+    MockResponse::fixture(200, "activities_distance.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /1/user/-/activities/steps/date/2016-01-01/2016-12-31.json".to_owned()));
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/body/"), 0);
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_steps.csv")),
+    vec![
+      json!(["2016-01-01", "4052"]),
+      json!(["2016-01-02", "11873"]),
+      json!(["2016-01-03", "0"]),
+    ]
+  );
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_distance.csv")),
+    vec![
+      json!(["2016-01-01", "3.12"]),
+      json!(["2016-01-02", "8.9"]),
+      json!(["2016-01-03", "0"]),
+    ]
+  );
+}
+
+#[test]
+fn keeps_repeated_activity_values_and_replaces_existing_days() {
+  let env = TestEnv::new("repeated-activity");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "activity_metrics": ["steps"],
+  }]));
+  // A partial total for the last day, written by an earlier sync.
+  std::fs::write(
+    env.csv_path.with_file_name("weight_steps.csv"),
+    "dateTime,value\n2016-01-04,1200.0\n",
+  )
+  .unwrap();
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/steps/date/",
+    MockResponse::fixture(200, "activities_steps_repeated.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_steps.csv")),
+    vec![
+      json!(["2016-01-01", "0"]),
+      json!(["2016-01-02", "0"]),
+      json!(["2016-01-03", "5000"]),
+      json!(["2016-01-04", "5000"]),
+    ]
+  );
+}
+
+#[test]
+fn syncs_health_metrics_in_ranges_of_30_days() {
+  let env = TestEnv::new("syncs-health-metrics");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "health_metrics": ["spo2", "hrv", "cardio_score"],
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/spo2/date/",
+    MockResponse::fixture(200, "spo2.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/hrv/date/",
+    MockResponse::fixture(200, "hrv.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/cardioscore/date/",
+    MockResponse::fixture(200, "cardioscore.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let requests = env.mock.requests();
+  assert!(requests.contains(&"GET /1/user/-/spo2/date/2016-01-01/2016-01-30.json".to_owned()));
+  assert!(requests.contains(&"GET /1/user/-/hrv/date/2016-01-30/2016-02-28.json".to_owned()));
+
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_spo2.csv")),
+    vec![
+      json!(["2016-01-01", "95.7", "91.5", "99.2"]),
+      json!(["2016-01-02", "96.1", "93.0", "98.8"]),
+    ]
+  );
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_hrv.csv")),
+    vec![json!(["2016-01-01", "34.938", "31.567"])]
+  );
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_cardio_score.csv")),
+    vec![
+      json!(["2016-01-01", "44-48"]),
+      json!(["2016-01-02", "46.2"])
+    ]
+  );
+}
+
+#[test]
+fn syncs_heart_rate_zones() {
+  let env = TestEnv::new("syncs-heart-rate");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "heart_rate": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/heart/date/",
+    MockResponse::fixture(200, "activities_heart.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /1/user/-/activities/heart/date/2016-01-01/2016-12-31.json".to_owned()));
+  let path = env.csv_path.with_file_name("weight_heart_rate.csv");
+  let mut reader = csv::Reader::from_path(&path).unwrap();
+  assert_eq!(
+    reader.headers().unwrap(),
+    vec![
+      "date",
+      "resting_heart_rate",
+      "out_of_range_minutes",
+      "fat_burn_minutes",
+      "cardio_minutes",
+      "peak_minutes"
+    ]
+  );
+  // A day without data has empty fields.
+  assert_eq!(
+    read_csv(&path),
+    vec![
+      json!(["2016-01-01", "61", "1233", "85", "7", "0"]),
+      json!(["2016-01-02", "", "", "", "", ""]),
+    ]
+  );
+}
+
+#[test]
+fn syncs_recent_intraday_heart_rate() {
+  let env = TestEnv::new("syncs-heart-rate-intraday");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "heart_rate_intraday": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/heart/date/",
+    MockResponse::fixture(200, "activities_heart_intraday.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let first_day = Utc::now().naive_utc().date() - Duration::days(30);
+  assert!(env.mock.requests().contains(&format!(
+    "GET /1/user/-/activities/heart/date/{}/1d/1min.json",
+    first_day
+  )));
+  assert_eq!(
+    env
+      .mock
+      .requests_to("GET", "/1/user/-/activities/heart/date/"),
+    31
+  );
+  let rows = read_csv(
+    &env
+      .csv_path
+      .with_file_name("weight_heart_rate_intraday.csv"),
+  );
+  assert_eq!(rows.len(), 31 * 3);
+  assert_eq!(rows[1], json!([format!("{} 00:01:00", first_day), "63"]));
+}
+
+#[test]
+fn backfill_limits_intraday_heart_rate_to_recent_days() {
+  let env = TestEnv::new("backfill-intraday");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "heart_rate_intraday": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/heart/date/",
+    MockResponse::fixture(200, "activities_heart_intraday.json"),
+  );
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations();
+  SyncSession::start(&destinations, &accounts)
+    .backfill(
+      "csv",
+      NaiveDate::from_ymd(2017, 1, 1),
+      Some(NaiveDate::from_ymd(2018, 12, 31)),
+    )
+    .unwrap();
+
+  assert_eq!(
+    env
+      .mock
+      .requests_to("GET", "/1/user/-/activities/heart/date/"),
+    31
+  );
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /1/user/-/activities/heart/date/2018-12-01/1d/1min.json".to_owned()));
+}
+
+#[test]
+fn syncs_sleep_sessions_and_stages_from_all_pages() {
+  let env = TestEnv::new("syncs-sleep");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "sleep": true,
+    "sleep_stages": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1.2/user/-/sleep/list.json?afterDate=2015-12-31&sort=asc&offset=0&",
+    MockResponse::fixture(200, "sleep_list_page1.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1.2/user/-/sleep/list.json?afterDate=2015-12-31&sort=asc&offset=1&",
+    MockResponse::fixture(200, "sleep_list_page2.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert_eq!(
+    env.mock.requests_to("GET", "/1.2/user/-/sleep/list.json"),
+    2
+  );
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_sleep.csv")),
+    vec![
+      json!([
+        "10571542000",
+        "2016-01-02",
+        "2016-01-01 23:15:30",
+        "2016-01-02 06:57:30",
+        "true",
+        "stages",
+        "93",
+        "411",
+        "51",
+        "0",
+        "462",
+        "82",
+        "231",
+        "98",
+        "51"
+      ]),
+      json!([
+        "10572398000",
+        "2016-01-02",
+        "2016-01-02 14:30:00",
+        "2016-01-02 15:30:00",
+        "false",
+        "classic",
+        "88",
+        "50",
+        "10",
+        "0",
+        "60",
+        "",
+        "",
+        "",
+        ""
+      ]),
+    ]
+  );
+  let stages = read_csv(&env.csv_path.with_file_name("weight_sleep_stages.csv"));
+  assert_eq!(stages.len(), 5);
+  assert_eq!(
+    stages[0],
+    json!(["2016-01-01 23:15:30", "10571542000", "wake", "600"])
+  );
+}
+
+#[test]
+fn syncs_recent_nutrition_and_water() {
+  let env = TestEnv::new("syncs-nutrition");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "nutrition": true,
+    "food_logs": true,
+    "water_logs": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/foods/log/date/",
+    MockResponse::fixture(200, "food_log.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/foods/log/water/date/",
+    MockResponse::fixture(200, "water_logs.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let first_day = Utc::now().naive_utc().date() - Duration::days(90);
+  assert!(env
+    .mock
+    .requests()
+    .contains(&format!("GET /1/user/-/foods/log/date/{}.json", first_day)));
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/foods/log/date/"), 91);
+
+  let nutrition = read_csv(&env.csv_path.with_file_name("weight_nutrition.csv"));
+  assert_eq!(nutrition.len(), 91);
+  assert_eq!(
+    nutrition[0],
+    json!([
+      first_day.to_string(),
+      "310.0",
+      "56.2",
+      "0.6",
+      "8.8",
+      "23.0",
+      "89.0",
+      "48.0"
+    ])
+  );
+  // The fixture logs the same foods and water on every day, with the same IDs.
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_food_logs.csv")),
+    vec![
+      json!([
+        "7401220000",
+        "2016-01-01",
+        "Breakfast",
+        "Apple",
+        "",
+        "2.0",
+        "medium",
+        "190.0",
+        "50.2",
+        "0.6",
+        "8.8",
+        "1.0",
+        "4.0"
+      ]),
+      json!([
+        "7401230000",
+        "2016-01-01",
+        "Lunch",
+        "Greek Yogurt, Plain",
+        "Chobani",
+        "1.0",
+        "container",
+        "120.0",
+        "6.0",
+        "0.0",
+        "0.0",
+        "22.0",
+        "85.0"
+      ]),
+    ]
+  );
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_water_logs.csv")).len(),
+    2
+  );
+}
+
+#[test]
+fn syncs_activity_logs_as_rows_and_tcx_files() {
+  let env = TestEnv::new("syncs-activity-logs");
+  let tcx_dir = env.project_dirs.data_dir().join("tcx");
+  env.set_destinations(json!([
+    {
+      "id": "csv",
+      "kind": { "CsvFile": { "path": env.csv_path } },
+      "body_metrics": [],
+      "activity_logs": true,
+    },
+    {
+      "id": "tcx",
+      "kind": { "TcxDirectory": { "path": tcx_dir } },
+      "body_metrics": [],
+      "activity_logs": true,
+    },
+  ]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/list.json",
+    MockResponse::fixture(200, "activities_list.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/19018673358.tcx",
+    MockResponse::fixture(200, "activity.tcx"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert!(env.mock.requests().contains(
+    &"GET /1/user/-/activities/list.json?afterDate=2015-12-31&sort=asc&offset=0&limit=100"
+      .to_owned()
+  ));
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_activities.csv")),
+    vec![
+      json!([
+        "19018673358",
+        "2016-01-03 12:08:00",
+        "Run",
+        "mobile_run",
+        "1596",
+        "1536",
+        "154",
+        "384",
+        "4.02",
+        "Kilometer",
+        "4271"
+      ]),
+      json!([
+        "19018673402",
+        "2016-01-04 07:30:00",
+        "Yoga",
+        "manual",
+        "1800",
+        "1800",
+        "",
+        "95",
+        "",
+        "",
+        ""
+      ]),
+    ]
+  );
+
+  // Only the activity with a TCX link is downloaded.
+  assert_eq!(
+    env.mock.requests_to("GET", "/1/user/-/activities/19018673"),
+    1
+  );
+  let files: Vec<_> = std::fs::read_dir(&tcx_dir)
+    .unwrap()
+    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+    .collect();
+  assert_eq!(files, vec!["2016-01-03_19018673358.tcx"]);
+  assert!(
+    std::fs::read_to_string(tcx_dir.join("2016-01-03_19018673358.tcx"))
+      .unwrap()
+      .contains("<TrainingCenterDatabase")
+  );
+}
+
+#[test]
+fn tcx_directory_syncs_with_minimal_config() {
+  let env = TestEnv::new("tcx-minimal");
+  let tcx_dir = env.project_dirs.data_dir().join("tcx");
+  env.set_destinations(json!([{
+    "id": "tcx",
+    "kind": { "TcxDirectory": { "path": tcx_dir } },
+    "activity_logs": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/list.json",
+    MockResponse::fixture(200, "activities_list.json"),
+  );
+  env.mock.on(
+    "GET",
+    "/1/user/-/activities/19018673358.tcx",
+    MockResponse::fixture(200, "activity.tcx"),
+  );
+  env.authorize().unwrap();
+
+  let (_, destinations) = env.sync_all();
+
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/body/"), 0);
+  assert!(tcx_dir.join("2016-01-03_19018673358.tcx").exists());
+  let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+  assert!(statuses[0]["last_error"].is_null());
+}
+
+#[test]
+fn requests_us_units_by_default() {
+  let env = TestEnv::new("units-default");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  // The profile's weight unit is METRIC, which is only used when asked for.
+  let languages = env
+    .mock
+    .header_values("GET", BODY_WEIGHT_PATH, "Accept-Language");
+  assert!(!languages.is_empty());
+  assert!(languages.iter().all(|l| l.as_deref() == Some("en_US")));
+}
+
+#[test]
+fn configured_units_are_requested() {
+  let mut env = TestEnv::new("units-configured");
+  env.config.fitbit_units = "en_GB".to_owned();
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let languages = env
+    .mock
+    .header_values("GET", BODY_WEIGHT_PATH, "Accept-Language");
+  assert!(!languages.is_empty());
+  assert!(languages.iter().all(|l| l.as_deref() == Some("en_GB")));
+}
+
+#[test]
+fn requests_units_and_locale_from_profile_when_configured() {
+  let mut env = TestEnv::new("units-from-profile");
+  env.config.fitbit_units = "profile".to_owned();
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let languages = env
+    .mock
+    .header_values("GET", BODY_WEIGHT_PATH, "Accept-Language");
+  assert!(!languages.is_empty());
+  assert!(languages.iter().all(|l| l.as_deref() == Some("METRIC")));
+  let locales = env
+    .mock
+    .header_values("GET", BODY_WEIGHT_PATH, "Accept-Locale");
+  assert!(locales.iter().all(|l| l.as_deref() == Some("en_US")));
+}
+
+#[test]
+fn syncs_days_in_the_profile_timezone() {
+  // UTC+14, so it's always a later time of day there than in UTC, and often the
+  // next day.
+  let env = TestEnv::with_profile("syncs-in-timezone", "profile_kiritimati.json");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "water_logs": true,
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/foods/log/water/date/",
+    MockResponse::fixture(200, "water_logs.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  let today = (Utc::now().naive_utc() + Duration::hours(14)).date();
+  let requests = env.mock.requests();
+  assert!(requests.contains(&format!(
+    "GET /1/user/-/foods/log/water/date/{}.json",
+    today - Duration::days(90)
+  )));
+  assert_eq!(
+    requests.last().unwrap(),
+    &format!("GET /1/user/-/foods/log/water/date/{}.json", today)
+  );
+}
+
+#[test]
+fn syncs_weight_logs_once_each() {
+  let env = TestEnv::new("syncs-weight-logs");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "weight_mode": "logs",
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/body/log/weight/date/",
+    MockResponse::fixture(200, "weight_logs.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  assert!(env
+    .mock
+    .requests()
+    .contains(&"GET /1/user/-/body/log/weight/date/2016-01-01/2016-01-31.json".to_owned()));
+  assert_eq!(env.mock.requests_to("GET", BODY_WEIGHT_PATH), 0);
+  // Every window returns the same logs, but each is only written once.
+  assert_eq!(
+    read_csv(&env.csv_path.with_file_name("weight_logs.csv")),
+    vec![
+      json!([
+        "1451806200000",
+        "2016-01-03",
+        "07:30:00",
+        "80.1",
+        "24.12",
+        "22.5",
+        "Aria"
+      ]),
+      json!([
+        "1451893500000",
+        "2016-01-04",
+        "07:45:00",
+        "79.8",
+        "23.93",
+        "",
+        "API"
+      ]),
+    ]
+  );
+}
+
+#[test]
+fn refreshes_expired_token_and_retries() {
+  let env = TestEnv::new("refreshes-expired-token");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(401, "error_expired_token.json"),
+  );
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  env.sync_all();
+
+  // One request for the authorization code, and one to refresh.
+  assert_eq!(env.mock.requests_to("POST", "/oauth2/token"), 2);
+  assert_eq!(env.csv_rows().len(), 3);
+}
+
+#[test]
+fn rejected_refresh_token_requires_reauthorization() {
+  let env = TestEnv::new("invalid-token");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(401, "error_invalid_token.json"),
+  );
+  env.mock.on(
+    "POST",
+    "/oauth2/token",
+    MockResponse::fixture(400, "error_invalid_grant.json"),
+  );
+  env.authorize().unwrap();
+
+  let (accounts, destinations, result) = env.try_sync_all();
+  assert!(result.is_err());
+
+  let account = accounts.get(DEFAULT_ACCOUNT).unwrap();
+  let oauth = account.fitbit_client.oauth.lock().unwrap();
+  assert!(oauth.needs_reauthorization());
+  assert!(!oauth.has_secret());
+  assert_eq!(env.mock.requests_to("GET", BODY_WEIGHT_PATH), 1);
+  assert_eq!(env.mock.requests_to("POST", "/oauth2/token"), 2);
+
+  let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+  assert_eq!(statuses[0]["consecutive_failures"], 1);
+  assert!(statuses[0]["last_error"]
+    .as_str()
+    .unwrap()
+    .contains("Invalid token"));
+}
+
+#[test]
+fn failed_refresh_keeps_tokens_and_retries() {
+  let env = TestEnv::new("refresh-network-error");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(401, "error_expired_token.json"),
+  );
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env
+    .mock
+    .on("POST", "/oauth2/token", MockResponse::broken_connection());
+  env.authorize().unwrap();
+  env.mock.on(
+    "POST",
+    "/oauth2/token",
+    MockResponse::fixture(200, "token.json"),
+  );
+
+  let (accounts, _) = env.sync_all();
+
+  let account = accounts.get(DEFAULT_ACCOUNT).unwrap();
+  let oauth = account.fitbit_client.oauth.lock().unwrap();
+  assert!(!oauth.needs_reauthorization());
+  assert!(oauth.has_secret());
+  assert_eq!(
+    env.mock.requests_to(
+      "GET",
+      &format!("{}2016-01-01/2016-12-31.json", BODY_WEIGHT_PATH)
+    ),
+    2
+  );
+  assert_eq!(env.csv_rows().len(), 3);
+}
+
+#[test]
+fn refresh_by_another_process_sharing_the_vault_is_picked_up() {
+  let env = TestEnv::new("shared-vault-refresh");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(401, "error_expired_token.json"),
+  );
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.mock.on(
+    "POST",
+    "/oauth2/token",
+    MockResponse::fixture(200, "token_refreshed.json"),
+  );
+  // Fitbit rejects a refresh token that has already been used.
+  env.mock.on(
+    "POST",
+    "/oauth2/token",
+    MockResponse::fixture(400, "error_invalid_grant.json"),
+  );
+  env.authorize().unwrap();
+
+  // The server and a backfill from the command line each open the vault.
+  let server_accounts = env.accounts();
+  let command_vault = Vault::open(&env.project_dirs).unwrap();
+  let command_accounts = Accounts::load(&env.config, &env.project_dirs, &command_vault).unwrap();
+
+  {
+    let account = command_accounts.get(DEFAULT_ACCOUNT).unwrap();
+    let mut oauth = account.fitbit_client.oauth.lock().unwrap();
+    oauth.refresh_tokens().unwrap();
+    assert_eq!(oauth.get_secret().unwrap(), "mock-refreshed-access-token");
+  }
+
+  // The server's access token is rejected, and it finds the new tokens in the vault
+  // instead of using its refresh token again.
+  let destinations = env.destinations();
+  SyncSession::start(&destinations, &server_accounts)
+    .sync_all()
+    .unwrap();
+
+  let account = server_accounts.get(DEFAULT_ACCOUNT).unwrap();
+  let mut oauth = account.fitbit_client.oauth.lock().unwrap();
+  assert!(!oauth.needs_reauthorization());
+  assert_eq!(oauth.get_secret().unwrap(), "mock-refreshed-access-token");
+  assert_eq!(env.mock.requests_to("POST", "/oauth2/token"), 2);
+  assert_eq!(
+    env
+      .mock
+      .header_values("GET", BODY_WEIGHT_PATH, "Authorization")[1]
+      .as_deref(),
+    Some("Bearer mock-refreshed-access-token")
+  );
+}
+
+#[test]
+fn insufficient_scope_fails_destination_and_keeps_tokens() {
+  let env = TestEnv::new("insufficient-scope");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(403, "error_insufficient_scope.json"),
+  );
+  env.authorize().unwrap();
+
+  let (accounts, destinations, result) = env.try_sync_all();
+  assert!(result.is_err());
+
+  let account = accounts.get(DEFAULT_ACCOUNT).unwrap();
+  let oauth = account.fitbit_client.oauth.lock().unwrap();
+  assert!(!oauth.needs_reauthorization());
+  assert!(oauth.has_secret());
+  assert_eq!(env.mock.requests_to("POST", "/oauth2/token"), 1);
+
+  let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+  assert!(statuses[0]["last_error"]
+    .as_str()
+    .unwrap()
+    .contains("Insufficient scope"));
+}
+
+#[test]
+fn failing_destination_backs_off_exponentially() {
+  let env = TestEnv::new("backoff");
+  for _ in 0..11 {
+    env.mock.on(
+      "GET",
+      BODY_WEIGHT_PATH,
+      MockResponse::fixture(403, "error_insufficient_scope.json"),
+    );
+  }
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  // The delay doubles from 5 minutes with each failure, up to a day.
+  for &minutes in [5, 10, 20, 40, 80, 160, 320, 640, 1280, 1440, 1440].iter() {
+    let (_, destinations, result) = env.try_sync_all();
+    assert!(result.is_err());
+
+    let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+    let failed_at: NaiveDateTime =
+      serde_json::from_value(statuses[0]["last_error_time"].clone()).unwrap();
+    let retry_at: NaiveDateTime = serde_json::from_value(statuses[0]["retry_at"].clone()).unwrap();
+    assert_eq!(retry_at - failed_at, Duration::minutes(minutes));
+  }
+  assert_eq!(env.mock.requests_to("GET", BODY_WEIGHT_PATH), 11);
+
+  let (_, destinations) = env.sync_all();
+  let destinations = destinations.lock().unwrap();
+  assert_eq!(destinations.retry_at("csv"), None);
+  let statuses = serde_json::to_value(destinations.statuses()).unwrap();
+  assert_eq!(statuses[0]["consecutive_failures"], 0);
+}
+
+#[test]
+fn health_metric_outside_granted_scopes_asks_to_authorize_again() {
+  let env = TestEnv::new("health-metric-scope");
+  env.set_destinations(json!([{
+    "id": "csv",
+    "kind": { "CsvFile": { "path": env.csv_path } },
+    "body_metrics": [],
+    "health_metrics": ["spo2"],
+  }]));
+  env.mock.on(
+    "GET",
+    "/1/user/-/spo2/date/",
+    MockResponse::fixture(403, "error_insufficient_scope.json"),
+  );
+  // token.json grants the scopes that were requested before health metrics.
+  env.authorize().unwrap();
+
+  let (accounts, destinations, result) = env.try_sync_all();
+  assert!(result.is_err());
+
+  let account = accounts.get(DEFAULT_ACCOUNT).unwrap();
+  let oauth = account.fitbit_client.oauth.lock().unwrap();
+  assert!(oauth.has_secret());
+  assert_eq!(
+    oauth.missing_scopes(),
+    vec![
+      "cardio_fitness",
+      "oxygen_saturation",
+      "respiratory_rate",
+      "temperature"
+    ]
+  );
+
+  let statuses = serde_json::to_value(destinations.lock().unwrap().statuses()).unwrap();
+  assert!(statuses[0]["last_error"]
+    .as_str()
+    .unwrap()
+    .contains("Authorize fitsync again"));
+}
+
+#[test]
+fn waits_out_rate_limit_and_records_quota() {
+  let env = TestEnv::new("rate-limit");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(429, "error_too_many_requests.json").with_header("Retry-After", "0"),
+  );
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json")
+      .with_header("Fitbit-Rate-Limit-Limit", "150")
+      .with_header("Fitbit-Rate-Limit-Remaining", "120")
+      .with_header("Fitbit-Rate-Limit-Reset", "1800"),
+  );
+  env.authorize().unwrap();
+
+  let (accounts, _) = env.sync_all();
+
+  assert_eq!(env.csv_rows().len(), 3);
+  let rate_limit = accounts
+    .get(DEFAULT_ACCOUNT)
+    .unwrap()
+    .fitbit_client
+    .rate_limit()
+    .unwrap();
+  assert_eq!(rate_limit.limit, 150);
+  assert_eq!(rate_limit.remaining, 120);
+}
+
+#[test]
+fn tokens_are_available_while_waiting_out_rate_limit() {
+  let env = TestEnv::new("rate-limit-unlocked");
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(429, "error_too_many_requests.json").with_header("Retry-After", "2"),
+  );
+  env.mock.on(
+    "GET",
+    BODY_WEIGHT_PATH,
+    MockResponse::fixture(200, "body_weight.json"),
+  );
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let destinations = env.destinations();
+  std::thread::scope(|scope| {
+    let sync = scope.spawn(|| SyncSession::start(&destinations, &accounts).sync_all());
+
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let account = accounts.get(DEFAULT_ACCOUNT).unwrap();
+    assert!(account.fitbit_client.oauth.try_lock().is_ok());
+
+    sync.join().unwrap().unwrap();
+  });
+  assert_eq!(env.csv_rows().len(), 3);
+}
+
+#[test]
+fn gets_weight_logs() {
+  let env = TestEnv::new("weight-logs");
+  env.mock.on(
+    "GET",
+    "/1/user/-/body/log/weight/date/2016-01-04/1w.json",
+    MockResponse::fixture(200, "weight_logs.json"),
+  );
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let logs = accounts
+    .get(DEFAULT_ACCOUNT)
+    .unwrap()
+    .fitbit_client
+    .get_weight_logs(GetWeightLogsRequest::for_period(
+      NaiveDate::from_ymd(2016, 1, 4),
+      TimePeriod::OneWeek,
+    ))
+    .unwrap();
+
+  assert_eq!(logs.len(), 2);
+  assert_eq!(
+    logs[1].date_time(),
+    NaiveDate::from_ymd(2016, 1, 4).and_hms(7, 45, 0)
+  );
+}
+
+#[test]
+fn gets_devices_and_caches_them() {
+  let env = TestEnv::new("devices");
+  env.mock.on(
+    "GET",
+    "/1/user/-/devices.json",
+    MockResponse::fixture(200, "devices.json"),
+  );
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let client = &accounts.get(DEFAULT_ACCOUNT).unwrap().fitbit_client;
+  let devices = client.devices().unwrap();
+  client.devices().unwrap();
+
+  assert_eq!(env.mock.requests_to("GET", "/1/user/-/devices.json"), 1);
+  assert_eq!(devices.len(), 2);
+  assert_eq!(devices[0].device_version, "Charge 5");
+  assert_eq!(devices[0].battery_level, Some(52));
+  assert_eq!(
+    devices[0].last_sync_time,
+    Some(NaiveDate::from_ymd(2016, 1, 4).and_hms(7, 12, 30))
+  );
+  assert_eq!(devices[1].device_type, "SCALE");
+  assert_eq!(devices[1].battery_level, None);
+}
+
+#[test]
+fn unknown_endpoint_is_not_found() {
+  let env = TestEnv::new("not-found");
+  env.authorize().unwrap();
+
+  let accounts = env.accounts();
+  let result = accounts
+    .get(DEFAULT_ACCOUNT)
+    .unwrap()
+    .fitbit_client
+    .get_weight_logs(GetWeightLogsRequest::for_period(
+      NaiveDate::from_ymd(2016, 1, 4),
+      TimePeriod::OneWeek,
+    ));
+
+  match result {
+    Err(FitbitError::NotFound(message)) => assert!(message.contains("could not be found")),
+    other => panic!("Expected NotFound, got {:?}", other),
+  }
+}
@@ -0,0 +1,122 @@
+//! Checks the encrypted credential vault and the tokens kept in it.
+
+mod support;
+
+use std::path::PathBuf;
+
+use fitsync::vault::{KeySource, Vault};
+use serde_json::json;
+use support::TestEnv;
+
+/// The path of a vault for `name` in a scratch directory, with any vault left there
+/// by an earlier run removed.
+fn vault_path(name: &str) -> PathBuf {
+  let dir = std::env::temp_dir()
+    .join(format!("fitsync-tests-{}", std::process::id()))
+    .join("vaults")
+    .join(name);
+  if dir.exists() {
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+  dir.join("credentials.vault")
+}
+
+fn passphrase(passphrase: &str) -> KeySource {
+  KeySource::Passphrase(passphrase.to_owned())
+}
+
+#[test]
+fn round_trips_entries_encrypted() {
+  let path = vault_path("round-trip");
+  let mut vault = Vault::open_with_key(path.clone(), &passphrase("secret")).unwrap();
+  vault
+    .set("token.fitbit", "mock-refresh-token".to_owned())
+    .unwrap();
+
+  let contents = std::fs::read_to_string(&path).unwrap();
+  assert!(!contents.contains("mock-refresh-token"));
+
+  let vault = Vault::open_with_key(path, &passphrase("secret")).unwrap();
+  assert_eq!(vault.get("token.fitbit"), Some("mock-refresh-token"));
+}
+
+#[test]
+fn wrong_passphrase_fails_to_open() {
+  let path = vault_path("wrong-passphrase");
+  let mut vault = Vault::open_with_key(path.clone(), &passphrase("secret")).unwrap();
+  vault.set("token.fitbit", "value".to_owned()).unwrap();
+
+  let error = Vault::open_with_key(path, &passphrase("guess"))
+    .err()
+    .unwrap();
+  assert!(error.to_string().contains("Unable to decrypt"));
+}
+
+#[test]
+fn rotated_key_replaces_old_key() {
+  let path = vault_path("rotate");
+  let mut vault = Vault::open_with_key(path.clone(), &passphrase("old")).unwrap();
+  vault.set("token.fitbit", "value".to_owned()).unwrap();
+
+  vault.rotate_key(&passphrase("new")).unwrap();
+
+  assert!(Vault::open_with_key(path.clone(), &passphrase("old")).is_err());
+  let vault = Vault::open_with_key(path, &passphrase("new")).unwrap();
+  assert_eq!(vault.get("token.fitbit"), Some("value"));
+}
+
+#[test]
+fn writes_keep_entries_written_by_others() {
+  let path = vault_path("shared");
+  let mut server = Vault::open_with_key(path.clone(), &passphrase("secret")).unwrap();
+  server.set("token.fitbit", "server".to_owned()).unwrap();
+  let mut command = Vault::open_with_key(path.clone(), &passphrase("secret")).unwrap();
+
+  command.set("token.google", "command".to_owned()).unwrap();
+  server
+    .set("token.fitbit.other", "server".to_owned())
+    .unwrap();
+  command.remove("token.fitbit").unwrap();
+
+  let vault = Vault::open_with_key(path, &passphrase("secret")).unwrap();
+  assert_eq!(vault.get("token.fitbit"), None);
+  assert_eq!(vault.get("token.google"), Some("command"));
+  assert_eq!(vault.get("token.fitbit.other"), Some("server"));
+}
+
+#[test]
+fn moves_plaintext_tokens_into_vault() {
+  let env = TestEnv::new("vault-legacy-tokens");
+  let legacy_path = env
+    .project_dirs
+    .data_dir()
+    .join("tokens")
+    .join("fitbit.json");
+  std::fs::create_dir_all(legacy_path.parent().unwrap()).unwrap();
+  std::fs::write(
+    &legacy_path,
+    json!({
+      "tokens": {
+        "access_token": "legacy-access-token",
+        "refresh_token": "legacy-refresh-token",
+        "token_type": "Bearer",
+      },
+      "expires_at": null,
+    })
+    .to_string(),
+  )
+  .unwrap();
+
+  let mut oauth = env.fitbit_oauth();
+
+  assert_eq!(oauth.get_secret().unwrap(), "legacy-access-token");
+  assert!(!legacy_path.exists());
+  let vault = Vault::open(&env.project_dirs).unwrap();
+  let stored = vault
+    .lock()
+    .unwrap()
+    .get("token.fitbit")
+    .unwrap()
+    .to_owned();
+  assert!(stored.contains("legacy-refresh-token"));
+}